@@ -0,0 +1,229 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+pub type CodecError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Serialization format used to store values.
+///
+/// `id` is written into every entry so a value is never decoded with a codec
+/// other than the one that encoded it. Ids `0..=127` are reserved for the
+/// codecs in this crate.
+pub trait Codec: Send + Sync {
+    fn id(&self) -> u8;
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError>;
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError>;
+}
+
+/// JSON via `serde_json`. The default codec.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl Codec for Json {
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// `bincode` 1.x with its default options.
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Codec for Bincode {
+    fn id(&self) -> u8 {
+        2
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        Ok(bincode::serialize(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+/// MessagePack via `rmp-serde`, with structs encoded as maps.
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    fn id(&self) -> u8 {
+        3
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        Ok(rmp_serde::to_vec_named(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+}
+
+/// CBOR via `ciborium`.
+#[cfg(feature = "cbor")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    fn id(&self) -> u8 {
+        4
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(value, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        Ok(ciborium::from_reader(bytes)?)
+    }
+}
+
+/// `postcard`, a compact format for constrained environments.
+#[cfg(feature = "postcard")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Postcard;
+
+#[cfg(feature = "postcard")]
+impl Codec for Postcard {
+    fn id(&self) -> u8 {
+        5
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        Ok(postcard::to_allocvec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        Ok(postcard::from_bytes(bytes)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::{simple_cache::BlockingCache, test_util::TempDir, FsCache, MemoryCache};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Value {
+        id: u64,
+        name: String,
+        tags: Vec<String>,
+        score: Option<f64>,
+    }
+
+    fn value() -> Value {
+        Value {
+            id: 42,
+            name: "forty-two".to_string(),
+            tags: vec!["a".to_string(), "b".to_string()],
+            score: Some(0.5),
+        }
+    }
+
+    // Same format as `Json`, but another codec as far as entries are concerned
+    #[derive(Clone)]
+    struct OtherJson;
+
+    impl Codec for OtherJson {
+        fn id(&self) -> u8 {
+            200
+        }
+
+        fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+            Json.encode(value)
+        }
+
+        fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+            Json.decode(bytes)
+        }
+    }
+
+    fn check_round_trip(codec: impl Codec) {
+        let bytes = codec.encode(&value()).unwrap();
+        assert_eq!(codec.decode::<Value>(&bytes).unwrap(), value());
+        assert!(codec.decode::<Value>(&bytes[..bytes.len() / 2]).is_err());
+    }
+
+    // A directory written with one codec reads as empty with another
+    fn check_codecs_dont_mix<A, B>(a: A, b: B)
+    where
+        A: Codec + Clone + 'static,
+        B: Codec + Clone + 'static,
+    {
+        assert_ne!(a.id(), b.id());
+        let dir = TempDir::new();
+        let builder = || FsCache::builder(dir.path().to_path_buf());
+        builder()
+            .codec(a.clone())
+            .build()
+            .unwrap()
+            .set("key", value(), None)
+            .unwrap();
+
+        let other = builder().codec(b).build().unwrap();
+        assert_eq!(other.get::<Value>("key").unwrap(), None);
+        let same = builder().codec(a).build().unwrap();
+        assert_eq!(same.get::<Value>("key").unwrap(), Some(value()));
+    }
+
+    #[test]
+    fn json_round_trips() {
+        check_round_trip(Json);
+    }
+
+    #[test]
+    fn entries_of_other_codecs_read_as_misses() {
+        check_codecs_dont_mix(Json, OtherJson);
+
+        let cache = MemoryCache::new();
+        let entry = BlockingCache::encode_raw(&cache, value(), Default::default()).unwrap();
+        let other = MemoryCache::builder().codec(OtherJson).build();
+        BlockingCache::set_raw(&other, "key", entry).unwrap();
+        assert_eq!(BlockingCache::get::<Value>(&other, "key").unwrap(), None);
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_round_trips() {
+        check_round_trip(Bincode);
+        check_codecs_dont_mix(Json, Bincode);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_round_trips() {
+        check_round_trip(MessagePack);
+        check_codecs_dont_mix(Json, MessagePack);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_round_trips() {
+        check_round_trip(Cbor);
+        check_codecs_dont_mix(Json, Cbor);
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn postcard_round_trips() {
+        check_round_trip(Postcard);
+        check_codecs_dont_mix(Json, Postcard);
+    }
+}
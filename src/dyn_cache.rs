@@ -0,0 +1,269 @@
+use std::{future::Future, hash::Hash, pin::Pin};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    codec::{Codec, Json},
+    error::CacheError,
+    expiry::ExpiryPolicy,
+    key_hasher::EncodedKey,
+    simple_cache::{RawEntry, SendCache},
+    single_flight::{self, SingleFlight},
+};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// An object-safe, byte-level view of a cache, for picking a backend at
+/// runtime.
+///
+/// Keys are the output of [`encode_key`](crate::key_hasher::encode_key) and
+/// values are entries as returned by `get_raw`. Every `SendCache` using
+/// [`CacheError`] implements it, and [`TypedCache`] turns a
+/// `Box<dyn DynCache>` back into a `Cache`.
+pub trait DynCache: Send + Sync {
+    fn set_bytes<'a>(
+        &'a self,
+        key: &'a [u8],
+        entry: RawEntry,
+    ) -> BoxFuture<'a, Result<(), CacheError>>;
+    fn get_bytes<'a>(
+        &'a self,
+        key: &'a [u8],
+    ) -> BoxFuture<'a, Result<Option<RawEntry>, CacheError>>;
+    fn invalidate_bytes<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<(), CacheError>>;
+    fn collect_garbage(&self) -> BoxFuture<'_, Result<(), CacheError>>;
+}
+
+impl<C> DynCache for C
+where
+    C: SendCache<Error = CacheError> + Sync,
+{
+    fn set_bytes<'a>(
+        &'a self,
+        key: &'a [u8],
+        entry: RawEntry,
+    ) -> BoxFuture<'a, Result<(), CacheError>> {
+        Box::pin(self.set_raw(EncodedKey::from_bytes(key.to_vec()), entry))
+    }
+
+    fn get_bytes<'a>(
+        &'a self,
+        key: &'a [u8],
+    ) -> BoxFuture<'a, Result<Option<RawEntry>, CacheError>> {
+        Box::pin(self.get_raw(EncodedKey::from_bytes(key.to_vec())))
+    }
+
+    fn invalidate_bytes<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<(), CacheError>> {
+        Box::pin(self.invalidate(EncodedKey::from_bytes(key.to_vec())))
+    }
+
+    fn collect_garbage(&self) -> BoxFuture<'_, Result<(), CacheError>> {
+        Box::pin(SendCache::collect_garbage(self))
+    }
+}
+
+/// Serializes values on top of a [`DynCache`], making it a `Cache` again.
+///
+/// The codec has to match the one the wrapped cache was written with,
+/// entries in any other format read as misses.
+pub struct TypedCache<C = Json> {
+    inner: Box<dyn DynCache>,
+    codec: C,
+    flights: SingleFlight,
+}
+
+impl TypedCache {
+    pub fn new(inner: Box<dyn DynCache>) -> Self {
+        Self::with_codec(inner, Json)
+    }
+}
+
+impl<C: Codec> TypedCache<C> {
+    pub fn with_codec(inner: Box<dyn DynCache>, codec: C) -> Self {
+        Self {
+            inner,
+            codec,
+            flights: SingleFlight::default(),
+        }
+    }
+
+    pub fn inner(&self) -> &dyn DynCache {
+        self.inner.as_ref()
+    }
+}
+
+impl<C: Codec> SendCache for TypedCache<C> {
+    type Error = CacheError;
+    fn set(
+        &self,
+        key: impl Hash,
+        value: impl Serialize,
+        expiry: impl Into<ExpiryPolicy>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        let key = EncodedKey::new(key);
+        let entry = self.encode_raw(value, expiry.into());
+        async move { self.inner.set_bytes(key.as_bytes(), entry?).await }
+    }
+
+    fn get<T>(&self, key: impl Hash) -> impl Future<Output = Result<Option<T>, Self::Error>> + Send
+    where
+        T: DeserializeOwned,
+    {
+        let key = EncodedKey::new(key);
+        async move {
+            match self.inner.get_bytes(key.as_bytes()).await? {
+                Some(entry) => self.decode_raw(&entry),
+                None => Ok(None),
+            }
+        }
+    }
+
+    fn invalidate(&self, key: impl Hash) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        let key = EncodedKey::new(key);
+        async move { self.inner.invalidate_bytes(key.as_bytes()).await }
+    }
+
+    async fn collect_garbage(&self) -> Result<(), Self::Error> {
+        self.inner.collect_garbage().await
+    }
+
+    fn get_or_insert_with<T, E, F, Fut>(
+        &self,
+        key: impl Hash,
+        expiry: impl Into<ExpiryPolicy>,
+        loader: F,
+    ) -> impl Future<Output = Result<T, E>> + Send
+    where
+        T: Serialize + DeserializeOwned + Send,
+        E: From<Self::Error> + Send,
+        F: FnOnce() -> Fut + Send,
+        Fut: Future<Output = Result<T, E>> + Send,
+    {
+        single_flight::get_or_insert_with(
+            self,
+            &self.flights,
+            EncodedKey::new(key),
+            expiry.into(),
+            loader,
+        )
+    }
+
+    fn get_raw(
+        &self,
+        key: impl Hash,
+    ) -> impl Future<Output = Result<Option<RawEntry>, Self::Error>> + Send {
+        let key = EncodedKey::new(key);
+        async move { self.inner.get_bytes(key.as_bytes()).await }
+    }
+
+    fn set_raw(
+        &self,
+        key: impl Hash,
+        entry: RawEntry,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        let key = EncodedKey::new(key);
+        async move { self.inner.set_bytes(key.as_bytes(), entry).await }
+    }
+
+    fn encode_raw(
+        &self,
+        value: impl Serialize,
+        expiry: ExpiryPolicy,
+    ) -> Result<RawEntry, Self::Error> {
+        RawEntry::encode(&self.codec, value, expiry)
+    }
+
+    fn decode_raw<T>(&self, entry: &RawEntry) -> Result<Option<T>, Self::Error>
+    where
+        T: DeserializeOwned,
+    {
+        entry.decode(&self.codec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::{test_util::TempDir, FsCache, MemoryCache, TieredCache};
+
+    async fn check_round_trips(cache: TypedCache) {
+        cache.set("a", 1, None).await.unwrap();
+        cache.set("b", "two", None).await.unwrap();
+        assert_eq!(cache.get::<i32>("a").await.unwrap(), Some(1));
+        assert_eq!(
+            cache.get::<String>("b").await.unwrap().as_deref(),
+            Some("two")
+        );
+        assert_eq!(cache.get::<i32>("c").await.unwrap(), None);
+
+        cache.invalidate("a").await.unwrap();
+        assert_eq!(cache.get::<i32>("a").await.unwrap(), None);
+        assert_eq!(cache.inner().get_bytes(b"a").await.unwrap(), None);
+
+        // Expiries make it through the byte-level view both ways
+        let hour = Duration::from_secs(60 * 60);
+        let policy = ExpiryPolicy::ttl(hour).with_tti(hour * 2);
+        cache.set("ttl", 1, policy).await.unwrap();
+        let entry = cache.get_raw("ttl").await.unwrap().unwrap();
+        let remaining = entry
+            .expires_at
+            .unwrap()
+            .duration_since(SystemTime::now())
+            .unwrap();
+        assert!(remaining > hour - Duration::from_secs(60) && remaining <= hour);
+        assert_eq!(entry.idle_timeout, Some(hour * 2));
+
+        cache.set("expired", 1, Duration::ZERO).await.unwrap();
+        assert_eq!(cache.get::<i32>("expired").await.unwrap(), None);
+
+        // Entries of another codec read as misses, not as errors
+        let mut entry = cache.encode_raw(1, ExpiryPolicy::NEVER).unwrap();
+        entry.codec = 200;
+        cache.set_raw("other", entry).await.unwrap();
+        assert!(cache.get_raw("other").await.unwrap().is_some());
+        assert_eq!(cache.get::<i32>("other").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn round_trips_through_fs_cache() {
+        let dir = TempDir::new();
+        let cache = FsCache::new(dir.path().to_path_buf()).unwrap();
+        check_round_trips(TypedCache::new(Box::new(cache))).await;
+    }
+
+    #[tokio::test]
+    async fn round_trips_through_memory_cache() {
+        check_round_trips(TypedCache::new(Box::new(MemoryCache::new()))).await;
+    }
+
+    // The wrapped cache's default applies to entries that leave the expiry to it
+    async fn check_default_expiry(cache: TypedCache) {
+        cache.set("default", 1, None).await.unwrap();
+        cache.set("never", 1, ExpiryPolicy::NEVER).await.unwrap();
+        cache
+            .set(
+                "tti",
+                1,
+                ExpiryPolicy::DEFAULT.with_tti(Duration::from_secs(60)),
+            )
+            .await
+            .unwrap();
+        assert_eq!(cache.get::<i32>("default").await.unwrap(), None);
+        assert_eq!(cache.get::<i32>("never").await.unwrap(), Some(1));
+        assert_eq!(cache.get::<i32>("tti").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn applies_the_default_expiry_of_the_wrapped_cache() {
+        let dir = TempDir::new();
+        let builder = |name| FsCache::builder(dir.join(name)).default_expiry(Duration::ZERO);
+        let cache = builder("fs").build().unwrap();
+        check_default_expiry(TypedCache::new(Box::new(cache))).await;
+
+        let l2 = builder("l2").build().unwrap();
+        let tiered = TieredCache::new(MemoryCache::new(), l2);
+        check_default_expiry(TypedCache::new(Box::new(tiered))).await;
+    }
+}
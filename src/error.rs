@@ -0,0 +1,69 @@
+use std::{error::Error, fmt, io};
+
+use crate::codec::CodecError;
+
+/// Errors returned by the caches in this crate.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum CacheError {
+    /// Reading or writing the underlying storage failed
+    Io(io::Error),
+    /// The value could not be serialized
+    Serialize(CodecError),
+    /// The stored value could not be deserialized into the requested type
+    Deserialize(CodecError),
+    /// A stored entry is damaged. It can never be read, so callers may treat
+    /// this as a miss
+    Corrupt(String),
+    /// The key can't be stored because too many other keys share its hash
+    KeyCollision,
+    /// The entry is larger than the cache is allowed to grow
+    CapacityExceeded { size: u64, capacity: u64 },
+    /// Waiting for another user of the cache took too long
+    Timeout,
+    /// The storage was written in a layout or with settings this cache can't use
+    Incompatible(String),
+    /// Any other failure of the underlying storage
+    Backend(Box<dyn Error + Send + Sync>),
+}
+
+impl CacheError {
+    pub(crate) fn corrupt(reason: impl Into<String>) -> Self {
+        Self::Corrupt(reason.into())
+    }
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(_) => write!(f, "cache storage I/O failed"),
+            Self::Serialize(_) => write!(f, "failed to serialize cache value"),
+            Self::Deserialize(_) => write!(f, "failed to deserialize cache value"),
+            Self::Corrupt(reason) => write!(f, "corrupt cache entry: {reason}"),
+            Self::KeyCollision => write!(f, "too many cache keys share the same hash"),
+            Self::CapacityExceeded { size, capacity } => write!(
+                f,
+                "cache entry of {size} bytes exceeds the capacity of {capacity} bytes"
+            ),
+            Self::Timeout => write!(f, "timed out waiting for the cache"),
+            Self::Incompatible(reason) => write!(f, "incompatible cache: {reason}"),
+            Self::Backend(_) => write!(f, "cache backend failed"),
+        }
+    }
+}
+
+impl Error for CacheError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Serialize(e) | Self::Deserialize(e) | Self::Backend(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CacheError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
@@ -0,0 +1,181 @@
+use chrono::{DateTime, Utc};
+use std::time::{Duration, SystemTime};
+
+/// When an entry expires, however often it is read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Expiry {
+    /// Whatever the cache defaults to (see e.g.
+    /// `FsCacheBuilder::default_expiry`), never for caches without a default
+    #[default]
+    Default,
+    Never,
+    /// This long after the entry is written
+    After(Duration),
+    /// At this instant, kept to the millisecond
+    At(SystemTime),
+}
+
+impl Expiry {
+    /// When an entry written now expires. A time-to-live too long to be
+    /// represented never runs out.
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        match *self {
+            Self::Default | Self::Never => None,
+            Self::After(ttl) => SystemTime::now().checked_add(ttl),
+            Self::At(expires_at) => Some(expires_at),
+        }
+    }
+}
+
+impl From<Duration> for Expiry {
+    fn from(ttl: Duration) -> Self {
+        Self::After(ttl)
+    }
+}
+
+impl From<Option<Duration>> for Expiry {
+    fn from(ttl: Option<Duration>) -> Self {
+        ttl.map_or(Self::Default, Self::After)
+    }
+}
+
+impl From<SystemTime> for Expiry {
+    fn from(expires_at: SystemTime) -> Self {
+        Self::At(expires_at)
+    }
+}
+
+impl From<DateTime<Utc>> for Expiry {
+    fn from(expires_at: DateTime<Utc>) -> Self {
+        Self::At(expires_at.into())
+    }
+}
+
+/// When an entry expires: at a fixed [`Expiry`], once nobody read it for a
+/// while (time-to-idle), or whichever comes first.
+///
+/// Everything that converts into an [`Expiry`] converts into a policy
+/// without a time-to-idle, so `set(key, value, None)`,
+/// `set(key, value, Some(ttl))` and `set(key, value, expires_at)` all work.
+/// `None` leaves the expiry to the cache, like [`ExpiryPolicy::DEFAULT`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExpiryPolicy {
+    expiry: Expiry,
+    time_to_idle: Option<Duration>,
+}
+
+impl ExpiryPolicy {
+    /// Entries expire the way the cache defaults to.
+    pub const DEFAULT: Self = Self {
+        expiry: Expiry::Default,
+        time_to_idle: None,
+    };
+
+    /// Entries never expire, whatever the cache defaults to.
+    pub const NEVER: Self = Self {
+        expiry: Expiry::Never,
+        time_to_idle: None,
+    };
+
+    /// Entries expire `ttl` after they were written.
+    pub fn ttl(ttl: Duration) -> Self {
+        Self::NEVER.with_expiry(Expiry::After(ttl))
+    }
+
+    /// Entries expire once they weren't read for `tti`. Every `get` that
+    /// finds the entry starts the wait over.
+    pub fn tti(tti: Duration) -> Self {
+        Self::NEVER.with_tti(tti)
+    }
+
+    pub fn with_ttl(self, ttl: Duration) -> Self {
+        self.with_expiry(Expiry::After(ttl))
+    }
+
+    pub fn with_expiry(mut self, expiry: impl Into<Expiry>) -> Self {
+        self.expiry = expiry.into();
+        self
+    }
+
+    pub fn with_tti(mut self, tti: Duration) -> Self {
+        self.time_to_idle = Some(tti);
+        self
+    }
+
+    pub fn expiry(&self) -> Expiry {
+        self.expiry
+    }
+
+    pub fn time_to_idle(&self) -> Option<Duration> {
+        self.time_to_idle
+    }
+
+    // Takes what this policy leaves to the cache from the cache's `default`
+    pub(crate) fn or(self, default: Self) -> Self {
+        if self.expiry != Expiry::Default {
+            return self;
+        }
+        Self {
+            expiry: default.expiry,
+            time_to_idle: self.time_to_idle.or(default.time_to_idle),
+        }
+    }
+}
+
+impl From<Expiry> for ExpiryPolicy {
+    fn from(expiry: Expiry) -> Self {
+        Self::NEVER.with_expiry(expiry)
+    }
+}
+
+impl From<Duration> for ExpiryPolicy {
+    fn from(ttl: Duration) -> Self {
+        Expiry::from(ttl).into()
+    }
+}
+
+impl From<Option<Duration>> for ExpiryPolicy {
+    fn from(ttl: Option<Duration>) -> Self {
+        Expiry::from(ttl).into()
+    }
+}
+
+impl From<SystemTime> for ExpiryPolicy {
+    fn from(expires_at: SystemTime) -> Self {
+        Expiry::from(expires_at).into()
+    }
+}
+
+impl From<DateTime<Utc>> for ExpiryPolicy {
+    fn from(expires_at: DateTime<Utc>) -> Self {
+        Expiry::from(expires_at).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_to_live_too_long_to_represent_never_runs_out() {
+        assert_eq!(Expiry::After(Duration::MAX).expires_at(), None);
+        assert!(Expiry::After(Duration::from_secs(1)).expires_at() > Some(SystemTime::now()));
+    }
+
+    #[test]
+    fn only_leaves_unspecified_expiries_to_the_cache() {
+        let day = Duration::from_secs(24 * 60 * 60);
+        let default = ExpiryPolicy::ttl(day).with_tti(day);
+        assert_eq!(ExpiryPolicy::from(None).or(default), default);
+        assert_eq!(ExpiryPolicy::DEFAULT.or(default), default);
+        assert_eq!(ExpiryPolicy::NEVER.or(default), ExpiryPolicy::NEVER);
+        assert_eq!(
+            ExpiryPolicy::from(Expiry::Never).or(default),
+            ExpiryPolicy::NEVER
+        );
+        assert_eq!(
+            ExpiryPolicy::DEFAULT.with_tti(day * 2).or(default),
+            ExpiryPolicy::ttl(day).with_tti(day * 2)
+        );
+    }
+}
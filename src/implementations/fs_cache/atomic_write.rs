@@ -0,0 +1,168 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime},
+};
+
+/// How hard `FsCache` tries to make a write survive a crash of the machine.
+///
+/// Writes are always atomic: readers see either the old or the new entry,
+/// never a partial one. Syncing only matters for power loss or kernel crashes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Leave flushing to the operating system
+    #[default]
+    Never,
+    /// Sync the entry's contents before it replaces the old one
+    File,
+    /// Also sync the cache directory, so the new entry's name is durable
+    FileAndDir,
+}
+
+// Temporary files start with this prefix so they are never mistaken for entries
+const TEMP_PREFIX: &str = ".tmp-";
+
+// A temporary file this old belongs to a write that crashed or was killed
+const ABANDONED_AFTER: Duration = Duration::from_secs(60 * 60);
+
+static NEXT_TEMP: AtomicU64 = AtomicU64::new(0);
+
+/// Writes `contents` to a temporary file next to `path` and renames it over
+/// `path`.
+pub(crate) fn write(path: &Path, contents: &[u8], fsync: FsyncPolicy) -> Result<(), io::Error> {
+    let dir = path
+        .parent()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no parent"))?;
+    let temp_path = dir.join(format!(
+        "{TEMP_PREFIX}{}-{}",
+        process::id(),
+        NEXT_TEMP.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let res = write_temp(&temp_path, contents, fsync).and_then(|_| fs::rename(&temp_path, path));
+    if res.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    res?;
+
+    if fsync == FsyncPolicy::FileAndDir {
+        sync_dir(dir)?;
+    }
+    Ok(())
+}
+
+/// Whether `path` is a temporary file whose write was never completed.
+pub(crate) fn is_abandoned(path: &Path) -> Result<bool, io::Error> {
+    if !is_temporary(path) {
+        return Ok(false);
+    }
+
+    let modified = match fs::metadata(path) {
+        Ok(metadata) => metadata.modified()?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    Ok(SystemTime::now()
+        .duration_since(modified)
+        .is_ok_and(|age| age > ABANDONED_AFTER))
+}
+
+/// Whether `path` is a temporary file of a write, completed or not.
+pub(crate) fn is_temporary(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with(TEMP_PREFIX))
+}
+
+fn write_temp(temp_path: &Path, contents: &[u8], fsync: FsyncPolicy) -> Result<(), io::Error> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(temp_path)?;
+    file.write_all(contents)?;
+    if fsync != FsyncPolicy::Never {
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+pub(crate) fn sync_dir(dir: &Path) -> Result<(), io::Error> {
+    fs::File::open(dir)?.sync_all()
+}
+
+// Directories can't be opened for syncing on other platforms,
+// renames are made durable by the file system itself there
+#[cfg(not(unix))]
+pub(crate) fn sync_dir(_dir: &Path) -> Result<(), io::Error> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn file_names(dir: &TempDir) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|file| file.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn replaces_files_without_leaving_temporary_files() {
+        let dir = TempDir::new();
+        let path = dir.join("entry");
+        for fsync in [
+            FsyncPolicy::Never,
+            FsyncPolicy::File,
+            FsyncPolicy::FileAndDir,
+        ] {
+            write(&path, b"old", fsync).unwrap();
+            write(&path, b"new", fsync).unwrap();
+            assert_eq!(fs::read(&path).unwrap(), b"new");
+            assert_eq!(file_names(&dir), ["entry"]);
+        }
+    }
+
+    #[test]
+    fn cleans_up_after_failed_writes() {
+        let dir = TempDir::new();
+        // A directory can't be renamed over by a file
+        fs::create_dir(dir.join("entry")).unwrap();
+        fs::write(dir.join("entry").join("inside"), "").unwrap();
+        assert!(write(&dir.join("entry"), b"new", FsyncPolicy::Never).is_err());
+        assert_eq!(file_names(&dir), ["entry"]);
+    }
+
+    #[test]
+    fn tells_abandoned_temporary_files_apart() {
+        let dir = TempDir::new();
+        let fresh = dir.join(format!("{TEMP_PREFIX}1-0"));
+        let old = dir.join(format!("{TEMP_PREFIX}1-1"));
+        let entry = dir.join("1");
+        for path in [&fresh, &old, &entry] {
+            fs::write(path, "").unwrap();
+        }
+        let long_ago = SystemTime::now() - ABANDONED_AFTER * 2;
+        for path in [&old, &entry] {
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(long_ago)
+                .unwrap();
+        }
+
+        assert!(!is_abandoned(&fresh).unwrap());
+        assert!(is_abandoned(&old).unwrap());
+        assert!(!is_abandoned(&entry).unwrap());
+        assert!(!is_abandoned(&dir.join(format!("{TEMP_PREFIX}gone"))).unwrap());
+    }
+}
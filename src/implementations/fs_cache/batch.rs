@@ -0,0 +1,135 @@
+use std::{num::NonZeroUsize, thread};
+
+use super::Inner;
+use crate::{error::CacheError, simple_cache::RawEntry};
+
+// Enough to keep a disk busy without spawning a thread per key
+const MAX_THREADS: usize = 8;
+
+// Batches this small aren't worth spawning threads for
+const MIN_PER_THREAD: usize = 4;
+
+impl<C: Sync> Inner<C> {
+    pub(super) fn read_many(
+        &self,
+        keys: Vec<Vec<u8>>,
+    ) -> Vec<Result<Option<RawEntry>, CacheError>> {
+        parallel_map(keys, |key| self.read(&key))
+    }
+
+    pub(super) fn write_many(
+        &self,
+        entries: Vec<(Vec<u8>, Result<RawEntry, CacheError>)>,
+    ) -> Vec<Result<(), CacheError>> {
+        parallel_map(entries, |(key, entry)| self.write(key, entry?))
+    }
+
+    pub(super) fn remove_many(&self, keys: Vec<Vec<u8>>) -> Vec<Result<(), CacheError>> {
+        parallel_map(keys, |key| self.remove(&key))
+    }
+}
+
+// Maps `items` on a few threads at once, since each one is mostly waiting on
+// the file system. Results are in the order of `items`
+fn parallel_map<I, R>(items: Vec<I>, f: impl Fn(I) -> R + Sync) -> Vec<R>
+where
+    I: Send,
+    R: Send,
+{
+    let threads = thread::available_parallelism()
+        .map_or(1, NonZeroUsize::get)
+        .min(MAX_THREADS)
+        .min(items.len() / MIN_PER_THREAD);
+    map_on_threads(items, threads, f)
+}
+
+fn map_on_threads<I, R>(items: Vec<I>, threads: usize, f: impl Fn(I) -> R + Sync) -> Vec<R>
+where
+    I: Send,
+    R: Send,
+{
+    if threads <= 1 {
+        return items.into_iter().map(f).collect();
+    }
+
+    let chunk_len = items.len().div_ceil(threads);
+    let mut chunks = Vec::with_capacity(threads);
+    let mut items = items.into_iter();
+    loop {
+        let chunk: Vec<_> = items.by_ref().take(chunk_len).collect();
+        if chunk.is_empty() {
+            break;
+        }
+        chunks.push(chunk);
+    }
+
+    let f = &f;
+    thread::scope(|scope| {
+        let handles: Vec<_> = chunks
+            .into_iter()
+            .map(|chunk| scope.spawn(move || chunk.into_iter().map(f).collect::<Vec<_>>()))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| match handle.join() {
+                Ok(results) => results,
+                Err(panic) => std::panic::resume_unwind(panic),
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    // Maps every item to itself and the thread it was mapped on
+    fn map_with_thread_ids(len: usize, threads: usize) -> Vec<(usize, thread::ThreadId)> {
+        map_on_threads((0..len).collect(), threads, |i| (i, thread::current().id()))
+    }
+
+    #[test]
+    fn keeps_the_order_of_the_items() {
+        for threads in [1, 2, 3, MAX_THREADS] {
+            for len in [0, 1, 7, 8, 9, 100] {
+                let items: Vec<_> = map_with_thread_ids(len, threads)
+                    .into_iter()
+                    .map(|(i, _)| i)
+                    .collect();
+                assert_eq!(items, (0..len).collect::<Vec<_>>(), "{len} on {threads}");
+            }
+        }
+    }
+
+    #[test]
+    fn spreads_large_batches_over_threads() {
+        let used: HashSet<_> = map_with_thread_ids(100, 4)
+            .into_iter()
+            .map(|(_, id)| id)
+            .collect();
+        assert_eq!(used.len(), 4);
+        assert!(!used.contains(&thread::current().id()));
+
+        // Small ones stay on the calling thread
+        let small = parallel_map((0..MIN_PER_THREAD * 2 - 1).collect(), |_| {
+            thread::current().id()
+        });
+        assert!(small.iter().all(|&id| id == thread::current().id()));
+    }
+
+    #[test]
+    fn failed_items_dont_affect_the_others() {
+        let results = map_on_threads((0..20).collect(), 4, |i: i32| {
+            if i % 5 == 0 {
+                Err(i)
+            } else {
+                Ok(i * 2)
+            }
+        });
+        for (i, res) in (0..20).zip(results) {
+            assert_eq!(res, if i % 5 == 0 { Err(i) } else { Ok(i * 2) });
+        }
+    }
+}
@@ -0,0 +1,419 @@
+use chrono::Utc;
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    time::Duration,
+};
+
+use crate::error::CacheError;
+
+// An entry file starts with a fixed preamble:
+// - the magic bytes `SCE\x01`
+// - the format version, as a `u16`
+// - the length of the fields that follow, as a `u16`
+// Version 1 defines these fields:
+// - the id of the codec the value was serialized with, as a `u8`
+// - when the entry was created in milliseconds since the epoch, as an `i64`
+// - when the entry expires in milliseconds since the epoch, as an `i64` (`i64::MAX` if never)
+// - the length of the encoded key, as a `u32`
+// - the length of the serialized value, as a `u64`
+// - the CRC-32 of the encoded key followed by the serialized value, as a `u32`
+// Version 2 appends:
+// - when the entry was last read in milliseconds since the epoch, as an `i64`
+// - how many times the entry was read, as a `u64`
+// These two are updated in place, so they are not covered by the checksum.
+// Version 3 appends:
+// - how long the entry may go unread in milliseconds, as a `u64` (`u64::MAX` if forever)
+// After the fields come the encoded key and the serialized value.
+// All integers are little-endian.
+//
+// New versions may only append fields. Readers skip fields they don't know
+// and fill in defaults for fields an older writer didn't know about.
+
+const MAGIC: [u8; 4] = *b"SCE\x01";
+const VERSION: u16 = 3;
+const PREAMBLE_LEN: usize = 8;
+const FIELDS_LEN_V1: usize = 33;
+const FIELDS_LEN_V2: usize = 49;
+const FIELDS_LEN: usize = 57;
+const NEVER: i64 = i64::MAX;
+const NEVER_IDLE: u64 = u64::MAX;
+
+pub(crate) struct Header {
+    pub codec: u8,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    pub key: Vec<u8>,
+    pub payload_len: u64,
+    pub checksum: u32,
+    pub accessed_at: i64,
+    pub access_count: u64,
+    // Entries written before version 2 have nowhere to record accesses
+    pub tracks_access: bool,
+    // In milliseconds
+    pub idle_timeout: Option<u64>,
+}
+
+impl Header {
+    pub fn new(
+        codec: u8,
+        expires_at: Option<i64>,
+        idle_timeout: Option<Duration>,
+        key: Vec<u8>,
+    ) -> Self {
+        let now = Utc::now().timestamp_millis();
+        Self {
+            codec,
+            created_at: now,
+            expires_at,
+            key,
+            payload_len: 0,
+            checksum: 0,
+            accessed_at: now,
+            access_count: 0,
+            tracks_access: true,
+            idle_timeout: idle_timeout.map(|idle_timeout| {
+                u64::try_from(idle_timeout.as_millis())
+                    .unwrap_or(NEVER_IDLE)
+                    .min(NEVER_IDLE - 1)
+            }),
+        }
+    }
+
+    /// When the entry expires unless it is read before.
+    pub fn deadline(&self) -> Option<i64> {
+        let idle_deadline = self.idle_timeout.map(|idle_timeout| {
+            self.accessed_at
+                .saturating_add(i64::try_from(idle_timeout).unwrap_or(i64::MAX))
+        });
+        match (self.expires_at, idle_deadline) {
+            (Some(expires_at), Some(idle_deadline)) => Some(expires_at.min(idle_deadline)),
+            (expires_at, idle_deadline) => expires_at.or(idle_deadline),
+        }
+    }
+
+    /// Whether the deadline has been reached. Like in `MemoryCache`, the
+    /// entry is expired from its deadline on.
+    pub fn is_expired(&self) -> bool {
+        self.deadline()
+            .is_some_and(|deadline| deadline <= Utc::now().timestamp_millis())
+    }
+}
+
+/// Serializes `header` and `payload` into a complete entry file. The payload
+/// length and checksum are computed here, not taken from `header`.
+pub(crate) fn encode(header: &Header, payload: &[u8]) -> Result<Vec<u8>, CacheError> {
+    let key_len = u32::try_from(header.key.len()).map_err(|_| CacheError::CapacityExceeded {
+        size: header.key.len() as u64,
+        capacity: u32::MAX as u64,
+    })?;
+
+    let mut entry =
+        Vec::with_capacity(PREAMBLE_LEN + FIELDS_LEN + header.key.len() + payload.len());
+    entry.extend_from_slice(&MAGIC);
+    entry.extend_from_slice(&VERSION.to_le_bytes());
+    entry.extend_from_slice(&(FIELDS_LEN as u16).to_le_bytes());
+    entry.push(header.codec);
+    entry.extend_from_slice(&header.created_at.to_le_bytes());
+    entry.extend_from_slice(&header.expires_at.unwrap_or(NEVER).to_le_bytes());
+    entry.extend_from_slice(&key_len.to_le_bytes());
+    entry.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    entry.extend_from_slice(&checksum(&header.key, payload).to_le_bytes());
+    entry.extend_from_slice(&header.accessed_at.to_le_bytes());
+    entry.extend_from_slice(&header.access_count.to_le_bytes());
+    entry.extend_from_slice(&header.idle_timeout.unwrap_or(NEVER_IDLE).to_le_bytes());
+    entry.extend_from_slice(&header.key);
+    entry.extend_from_slice(payload);
+    Ok(entry)
+}
+
+/// Reads the header, leaving `reader` at the start of the payload.
+pub(crate) fn read_header(reader: &mut impl Read) -> Result<Header, CacheError> {
+    let mut preamble = [0; PREAMBLE_LEN];
+    reader.read_exact(&mut preamble).map_err(truncated)?;
+    if preamble[..4] != MAGIC {
+        return Err(CacheError::corrupt("not a cache entry"));
+    }
+    // The version isn't needed to read the fields since they are append-only
+    let fields_len = u16::from_le_bytes([preamble[6], preamble[7]]) as usize;
+    if fields_len < FIELDS_LEN_V1 {
+        return Err(CacheError::corrupt("header is too short"));
+    }
+
+    let mut fields = vec![0; fields_len];
+    reader.read_exact(&mut fields).map_err(truncated)?;
+    let mut fields = Fields(&fields);
+
+    let codec = fields.take::<1>()[0];
+    let created_at = i64::from_le_bytes(fields.take());
+    let expires_at = i64::from_le_bytes(fields.take());
+    let key_len = u32::from_le_bytes(fields.take());
+    let payload_len = u64::from_le_bytes(fields.take());
+    let checksum = u32::from_le_bytes(fields.take());
+    let tracks_access = fields_len >= FIELDS_LEN_V2;
+    let (accessed_at, access_count) = if tracks_access {
+        (
+            i64::from_le_bytes(fields.take()),
+            u64::from_le_bytes(fields.take()),
+        )
+    } else {
+        (created_at, 0)
+    };
+    let idle_timeout = if fields_len >= FIELDS_LEN {
+        Some(u64::from_le_bytes(fields.take())).filter(|idle_timeout| *idle_timeout != NEVER_IDLE)
+    } else {
+        None
+    };
+
+    // The length isn't covered by the checksum, so it can't be trusted
+    // with allocating a buffer up front
+    let mut key = Vec::new();
+    reader
+        .by_ref()
+        .take(u64::from(key_len))
+        .read_to_end(&mut key)?;
+    if key.len() as u64 != u64::from(key_len) {
+        return Err(CacheError::corrupt("truncated"));
+    }
+    Ok(Header {
+        codec,
+        created_at,
+        expires_at: (expires_at != NEVER).then_some(expires_at),
+        key,
+        payload_len,
+        checksum,
+        accessed_at,
+        access_count,
+        tracks_access,
+        idle_timeout,
+    })
+}
+
+/// Reads the payload following `header` and checks it against the checksum.
+pub(crate) fn read_payload(reader: &mut impl Read, header: &Header) -> Result<Vec<u8>, CacheError> {
+    let mut payload = Vec::new();
+    reader
+        .by_ref()
+        .take(header.payload_len)
+        .read_to_end(&mut payload)?;
+    if payload.len() as u64 != header.payload_len {
+        return Err(CacheError::corrupt("truncated"));
+    }
+
+    if checksum(&header.key, &payload) != header.checksum {
+        return Err(CacheError::corrupt("checksum mismatch"));
+    }
+    Ok(payload)
+}
+
+/// The checksum stored in the header of an entry for `key` and `payload`.
+pub(crate) fn checksum(key: &[u8], payload: &[u8]) -> u32 {
+    let mut checksum = crc32fast::Hasher::new();
+    checksum.update(key);
+    checksum.update(payload);
+    checksum.finalize()
+}
+
+/// Records a read of the entry `file` was opened on, in place. `file` must
+/// have been opened for writing.
+pub(crate) fn record_access(file: &mut File, header: &Header) -> Result<(), io::Error> {
+    if !header.tracks_access {
+        return Ok(());
+    }
+    let mut access = [0; 16];
+    access[..8].copy_from_slice(&Utc::now().timestamp_millis().to_le_bytes());
+    access[8..].copy_from_slice(&header.access_count.saturating_add(1).to_le_bytes());
+    file.seek(SeekFrom::Start((PREAMBLE_LEN + FIELDS_LEN_V1) as u64))?;
+    file.write_all(&access)
+}
+
+struct Fields<'a>(&'a [u8]);
+
+impl Fields<'_> {
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        let (field, rest) = self.0.split_at(N);
+        self.0 = rest;
+        field.try_into().unwrap()
+    }
+}
+
+fn truncated(e: io::Error) -> CacheError {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        CacheError::corrupt("truncated")
+    } else {
+        CacheError::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn sample() -> (Header, Vec<u8>) {
+        let header = Header::new(
+            7,
+            Some(1_000),
+            Some(Duration::from_secs(60)),
+            b"key".to_vec(),
+        );
+        let entry = encode(&header, b"payload").unwrap();
+        (header, entry)
+    }
+
+    // Rewrites `entry` as if written by a version with `fields_len` bytes of fields
+    fn with_fields_len(entry: &[u8], version: u16, fields_len: usize) -> Vec<u8> {
+        let rest = &entry[PREAMBLE_LEN + FIELDS_LEN..];
+        let mut fields = entry[PREAMBLE_LEN..PREAMBLE_LEN + FIELDS_LEN].to_vec();
+        fields.resize(fields_len, 0xab);
+
+        let mut rewritten = MAGIC.to_vec();
+        rewritten.extend_from_slice(&version.to_le_bytes());
+        rewritten.extend_from_slice(&(fields_len as u16).to_le_bytes());
+        rewritten.extend_from_slice(&fields);
+        rewritten.extend_from_slice(rest);
+        rewritten
+    }
+
+    fn read(entry: &[u8]) -> Result<(Header, Vec<u8>), CacheError> {
+        let mut reader = Cursor::new(entry);
+        let header = read_header(&mut reader)?;
+        let payload = read_payload(&mut reader, &header)?;
+        Ok((header, payload))
+    }
+
+    #[test]
+    fn round_trips() {
+        let (written, entry) = sample();
+        let (header, payload) = read(&entry).unwrap();
+
+        assert_eq!(payload, b"payload");
+        assert_eq!(header.codec, 7);
+        assert_eq!(header.created_at, written.created_at);
+        assert_eq!(header.expires_at, Some(1_000));
+        assert_eq!(header.key, b"key");
+        assert_eq!(header.payload_len, 7);
+        assert_eq!(header.checksum, checksum(b"key", b"payload"));
+        assert_eq!(header.accessed_at, written.created_at);
+        assert_eq!(header.access_count, 0);
+        assert!(header.tracks_access);
+        assert_eq!(header.idle_timeout, Some(60_000));
+    }
+
+    #[test]
+    fn round_trips_entries_without_expiry() {
+        let header = Header::new(0, None, None, Vec::new());
+        let (header, payload) = read(&encode(&header, b"").unwrap()).unwrap();
+        assert_eq!(header.expires_at, None);
+        assert_eq!(header.idle_timeout, None);
+        assert!(payload.is_empty());
+    }
+
+    #[test]
+    fn reads_version_1_entries() {
+        let (written, entry) = sample();
+        let (header, payload) = read(&with_fields_len(&entry, 1, FIELDS_LEN_V1)).unwrap();
+
+        assert_eq!(payload, b"payload");
+        assert_eq!(header.expires_at, Some(1_000));
+        assert_eq!(header.accessed_at, written.created_at);
+        assert_eq!(header.access_count, 0);
+        assert!(!header.tracks_access);
+        assert_eq!(header.idle_timeout, None);
+    }
+
+    #[test]
+    fn reads_version_2_entries() {
+        let (_, entry) = sample();
+        let (header, payload) = read(&with_fields_len(&entry, 2, FIELDS_LEN_V2)).unwrap();
+
+        assert_eq!(payload, b"payload");
+        assert!(header.tracks_access);
+        assert_eq!(header.idle_timeout, None);
+    }
+
+    #[test]
+    fn skips_fields_of_newer_versions() {
+        let (_, entry) = sample();
+        let (header, payload) = read(&with_fields_len(&entry, 9, FIELDS_LEN + 16)).unwrap();
+
+        assert_eq!(payload, b"payload");
+        assert_eq!(header.key, b"key");
+        assert_eq!(header.idle_timeout, Some(60_000));
+    }
+
+    #[test]
+    fn detects_damaged_entries() {
+        let (_, entry) = sample();
+
+        let mut flipped = entry.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert!(matches!(read(&flipped), Err(CacheError::Corrupt(_))));
+
+        for len in [0, 3, PREAMBLE_LEN + 4, entry.len() - 1] {
+            assert!(matches!(read(&entry[..len]), Err(CacheError::Corrupt(_))));
+        }
+
+        let mut not_an_entry = entry.clone();
+        not_an_entry[0] = b'X';
+        assert!(matches!(read(&not_an_entry), Err(CacheError::Corrupt(_))));
+
+        // Without allocating what the key length claims
+        let mut huge_key = entry.clone();
+        let key_len_at = PREAMBLE_LEN + 1 + 8 + 8;
+        huge_key[key_len_at..key_len_at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(read(&huge_key), Err(CacheError::Corrupt(_))));
+
+        let too_short = with_fields_len(&entry, 1, FIELDS_LEN_V1 - 1);
+        assert!(matches!(read(&too_short), Err(CacheError::Corrupt(_))));
+    }
+
+    #[test]
+    fn records_accesses_in_place() {
+        let dir = TempDir::new();
+        let path = dir.join("entry");
+        let (_, entry) = sample();
+        std::fs::write(&path, &entry).unwrap();
+
+        for count in 1..=2 {
+            let mut file = File::options().read(true).write(true).open(&path).unwrap();
+            let header = read_header(&mut file).unwrap();
+            record_access(&mut file, &header).unwrap();
+
+            let (header, payload) = read(&std::fs::read(&path).unwrap()).unwrap();
+            assert_eq!(header.access_count, count);
+            assert!(header.accessed_at >= header.created_at);
+            // Accesses aren't covered by the checksum
+            assert_eq!(payload, b"payload");
+        }
+    }
+
+    #[test]
+    fn leaves_version_1_entries_alone_on_access() {
+        let dir = TempDir::new();
+        let path = dir.join("entry");
+        let (_, entry) = sample();
+        let entry = with_fields_len(&entry, 1, FIELDS_LEN_V1);
+        std::fs::write(&path, &entry).unwrap();
+
+        let mut file = File::options().read(true).write(true).open(&path).unwrap();
+        let header = read_header(&mut file).unwrap();
+        record_access(&mut file, &header).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), entry);
+    }
+
+    #[test]
+    fn expires_at_the_earlier_deadline() {
+        let mut header = Header::new(0, Some(5_000), Some(Duration::from_secs(1)), Vec::new());
+        header.accessed_at = 1_000;
+        assert_eq!(header.deadline(), Some(2_000));
+        header.accessed_at = 10_000;
+        assert_eq!(header.deadline(), Some(5_000));
+        assert!(header.is_expired());
+
+        let header = Header::new(0, None, Some(Duration::MAX), Vec::new());
+        assert!(!header.is_expired());
+    }
+}
@@ -0,0 +1,354 @@
+use std::{
+    fs::{self, File},
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use super::{entry, parse_slot_name, Inner, Slot};
+use crate::error::CacheError;
+
+/// Which entries `FsCache` evicts first once it is over capacity.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// The least recently read
+    #[default]
+    Lru,
+    /// The least often read
+    Lfu,
+    /// The oldest
+    Fifo,
+    /// The closest to expiring, entries without an expiry go last
+    EarliestExpiry,
+}
+
+impl EvictionPolicy {
+    // Whether reads have to be recorded in the entries to rank them
+    fn tracks_access(self) -> bool {
+        matches!(self, Self::Lru | Self::Lfu)
+    }
+}
+
+pub(crate) struct Limits {
+    max_bytes: Option<u64>,
+    max_entries: Option<u64>,
+    policy: EvictionPolicy,
+    // Usage as of the last scan plus everything this process wrote since.
+    // Writes by other processes and removals only show up at the next scan
+    usage: Mutex<Option<Usage>>,
+}
+
+#[derive(Default)]
+struct Usage {
+    bytes: u64,
+    entries: u64,
+}
+
+struct Candidate {
+    key: Vec<u8>,
+    created_at: i64,
+    size: u64,
+    rank: (i64, i64),
+}
+
+impl Limits {
+    pub fn new(
+        max_bytes: Option<u64>,
+        max_entries: Option<u64>,
+        policy: EvictionPolicy,
+    ) -> Option<Self> {
+        if max_bytes.is_none() && max_entries.is_none() {
+            return None;
+        }
+        Some(Self {
+            max_bytes,
+            max_entries,
+            policy,
+            usage: Mutex::default(),
+        })
+    }
+
+    pub fn tracks_access(&self) -> bool {
+        self.policy.tracks_access()
+    }
+
+    /// Makes the next write scan the directory to find out the usage.
+    pub fn forget_usage(&self) {
+        *self.usage() = None;
+    }
+
+    fn usage(&self) -> MutexGuard<'_, Option<Usage>> {
+        self.usage.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Fails for an entry that could never fit, however much is evicted.
+    pub fn check_size(&self, size: u64) -> Result<(), CacheError> {
+        match (self.max_bytes, self.max_entries) {
+            (Some(max_bytes), _) if size > max_bytes => Err(CacheError::CapacityExceeded {
+                size,
+                capacity: max_bytes,
+            }),
+            // A cache that may not hold any entry has no room for this one either
+            (_, Some(0)) => Err(CacheError::CapacityExceeded { size, capacity: 0 }),
+            _ => Ok(()),
+        }
+    }
+
+    // Eviction stops a bit below the limits,
+    // so the next few writes don't each need a scan of the whole directory
+    fn is_over(&self, usage: &Usage, slack: bool) -> bool {
+        let limit = |max: u64| if slack { max - max / 10 } else { max };
+        self.max_bytes
+            .is_some_and(|max_bytes| usage.bytes > limit(max_bytes))
+            || self
+                .max_entries
+                .is_some_and(|max_entries| usage.entries > limit(max_entries))
+    }
+
+    fn rank(&self, header: &entry::Header) -> (i64, i64) {
+        match self.policy {
+            EvictionPolicy::Lru => (header.accessed_at, header.created_at),
+            EvictionPolicy::Lfu => (
+                i64::try_from(header.access_count).unwrap_or(i64::MAX),
+                header.accessed_at,
+            ),
+            EvictionPolicy::Fifo => (header.created_at, 0),
+            EvictionPolicy::EarliestExpiry => {
+                (header.deadline().unwrap_or(i64::MAX), header.created_at)
+            }
+        }
+    }
+}
+
+impl<C> Inner<C> {
+    /// Accounts for an entry of `size` bytes just written under `key`, and
+    /// evicts other entries if that puts the cache over capacity.
+    ///
+    /// Eviction is best effort: the entry is stored by now, so failing to make
+    /// room for it only leaves the cache over capacity until the next write.
+    pub(super) fn enforce_limits(&self, key: &[u8], size: u64) {
+        let Some(limits) = &self.limits else {
+            return;
+        };
+
+        let over = match limits.usage().as_mut() {
+            Some(usage) => {
+                usage.bytes += size;
+                usage.entries += 1;
+                limits.is_over(usage, false)
+            }
+            None => true,
+        };
+        if !over {
+            return;
+        }
+
+        // Evicting takes a while, other writes shouldn't queue up behind it.
+        // What they add meanwhile may be lost from the count, the next scan
+        // finds it again
+        *limits.usage() = self.evict(limits, key).ok();
+    }
+
+    // Scans the whole directory, dropping garbage and then the lowest ranked
+    // entries other than `keep` until usage is below the limits.
+    // Files that can't be read or removed are skipped, they are tried again
+    // by the next scan
+    fn evict(&self, limits: &Limits, keep: &[u8]) -> Result<Usage, CacheError> {
+        let _lock = self.locks.gc_wait()?;
+
+        let mut usage = Usage::default();
+        let mut candidates = Vec::new();
+        for file in fs::read_dir(&self.cache_dir)? {
+            let Ok(path) = file.map(|file| file.path()) else {
+                continue;
+            };
+            if parse_slot_name(&path).is_none() {
+                continue;
+            }
+            let _ = self.collect_file(&path);
+
+            // Gone since it was listed, or collected just now
+            let Ok(mut file) = File::open(&path) else {
+                continue;
+            };
+            let Ok(size) = file.metadata().map(|metadata| metadata.len()) else {
+                continue;
+            };
+            // Became corrupt since it was collected, the next pass gets it
+            let Ok(header) = entry::read_header(&mut file) else {
+                continue;
+            };
+            usage.bytes += size;
+            usage.entries += 1;
+            if header.key != keep {
+                candidates.push(Candidate {
+                    rank: limits.rank(&header),
+                    key: header.key,
+                    created_at: header.created_at,
+                    size,
+                });
+            }
+        }
+
+        candidates.sort_by_key(|candidate| candidate.rank);
+        for candidate in candidates {
+            if !limits.is_over(&usage, true) {
+                break;
+            }
+            // The entry may have moved along its chain or been replaced since the scan
+            let Ok(_lock) = self.locks.entry(self.key_hasher.hash(&candidate.key)) else {
+                continue;
+            };
+            if let Ok(Slot::Occupied(path, _, header)) = self.lookup(&candidate.key) {
+                if header.created_at == candidate.created_at && self.remove_slot(&path).is_ok() {
+                    usage.bytes -= candidate.size;
+                    usage.entries -= 1;
+                }
+            }
+        }
+        Ok(usage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+    use crate::{simple_cache::BlockingCache, test_util::TempDir, ExpiryPolicy, FsCache};
+
+    // Writes "a", "b" and "c", reads `reads`, then writes "d" into a cache
+    // with room for three entries and returns which entry made way for it
+    fn evicted(policy: EvictionPolicy, expiries: [ExpiryPolicy; 3], reads: &[&str]) -> String {
+        let dir = TempDir::new();
+        let cache = FsCache::builder(dir.path().to_path_buf())
+            .max_entries(3)
+            .eviction_policy(policy)
+            .build()
+            .unwrap();
+        // Timestamps have millisecond resolution
+        let tick = || thread::sleep(Duration::from_millis(5));
+
+        for (key, expiry) in ["a", "b", "c"].into_iter().zip(expiries) {
+            cache.set(key, key, expiry).unwrap();
+            tick();
+        }
+        for key in reads {
+            assert!(cache.get::<String>(key).unwrap().is_some());
+            tick();
+        }
+        cache.set("d", "d", None).unwrap();
+
+        let evicted: Vec<_> = ["a", "b", "c"]
+            .into_iter()
+            .filter(|key| cache.get::<String>(key).unwrap().is_none())
+            .collect();
+        assert_eq!(evicted.len(), 1, "evicted {evicted:?}");
+        assert!(cache.get::<String>("d").unwrap().is_some());
+        evicted[0].to_string()
+    }
+
+    const NEVER: [ExpiryPolicy; 3] = [ExpiryPolicy::NEVER; 3];
+
+    #[test]
+    fn lru_evicts_the_least_recently_read() {
+        assert_eq!(evicted(EvictionPolicy::Lru, NEVER, &[]), "a");
+        assert_eq!(
+            evicted(EvictionPolicy::Lru, NEVER, &["a", "a", "b", "c"]),
+            "a"
+        );
+        assert_eq!(evicted(EvictionPolicy::Lru, NEVER, &["b", "a", "c"]), "b");
+    }
+
+    #[test]
+    fn lfu_evicts_the_least_often_read() {
+        assert_eq!(
+            evicted(EvictionPolicy::Lfu, NEVER, &["a", "a", "b", "c"]),
+            "b"
+        );
+        assert_eq!(
+            evicted(EvictionPolicy::Lfu, NEVER, &["c", "a", "c", "b"]),
+            "a"
+        );
+    }
+
+    #[test]
+    fn fifo_evicts_the_oldest() {
+        assert_eq!(evicted(EvictionPolicy::Fifo, NEVER, &["a", "a", "b"]), "a");
+    }
+
+    #[test]
+    fn earliest_expiry_evicts_the_closest_to_expiring() {
+        let hour = Duration::from_secs(60 * 60);
+        let expiries = [
+            ExpiryPolicy::NEVER,
+            ExpiryPolicy::ttl(hour * 2),
+            ExpiryPolicy::ttl(hour),
+        ];
+        assert_eq!(evicted(EvictionPolicy::EarliestExpiry, expiries, &[]), "c");
+
+        // Idle timeouts count from the last read
+        let expiries = [
+            ExpiryPolicy::tti(hour),
+            ExpiryPolicy::ttl(hour),
+            ExpiryPolicy::NEVER,
+        ];
+        let policy = EvictionPolicy::EarliestExpiry;
+        assert_eq!(evicted(policy, expiries, &[]), "a");
+        assert_eq!(evicted(policy, expiries, &["a"]), "b");
+    }
+
+    #[test]
+    fn refuses_entries_larger_than_max_bytes() {
+        let dir = TempDir::new();
+        let cache = FsCache::builder(dir.path().to_path_buf())
+            .max_bytes(64)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            cache.set("a", "x".repeat(64), None),
+            Err(CacheError::CapacityExceeded { capacity: 64, .. })
+        ));
+        assert_eq!(cache.get::<String>("a").unwrap(), None);
+    }
+
+    #[test]
+    fn refuses_every_entry_without_room_for_any() {
+        let dir = TempDir::new();
+        let cache = FsCache::builder(dir.path().to_path_buf())
+            .max_entries(0)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            cache.set("a", 1, None),
+            Err(CacheError::CapacityExceeded { capacity: 0, .. })
+        ));
+        assert_eq!(cache.get::<i32>("a").unwrap(), None);
+        let entries = fs::read_dir(dir.path())
+            .unwrap()
+            .filter(|file| parse_slot_name(&file.as_ref().unwrap().path()).is_some());
+        assert_eq!(entries.count(), 0);
+    }
+
+    #[test]
+    fn files_that_cant_be_evicted_dont_fail_writes() {
+        let dir = TempDir::new();
+        let cache = FsCache::builder(dir.path().to_path_buf())
+            .max_entries(2)
+            .build()
+            .unwrap();
+        // Named like an entry, but neither readable nor removable as one
+        fs::create_dir(dir.join("12345")).unwrap();
+        fs::write(dir.join("12345").join("file"), b"").unwrap();
+
+        for key in ["a", "b", "c", "d"] {
+            cache.set(key, key, None).unwrap();
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(cache.get::<String>("d").unwrap().as_deref(), Some("d"));
+        // The readable entries were still evicted around it
+        let kept = ["a", "b", "c"]
+            .into_iter()
+            .filter(|key| cache.get::<String>(key).unwrap().is_some())
+            .count();
+        assert!(kept <= 1, "kept {kept}");
+    }
+}
@@ -0,0 +1,433 @@
+use std::{
+    fs, io, mem,
+    path::PathBuf,
+    sync::{mpsc, Arc, Mutex, PoisonError},
+    thread,
+    time::Duration,
+};
+
+use super::{FsCache, Inner};
+use crate::codec::Codec;
+
+/// What one complete pass of the background collector over the cache
+/// directory did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcStats {
+    /// Files looked at
+    pub scanned: u64,
+    /// Expired or corrupt entries, abandoned temporary files and tag markers
+    /// of entries that are gone, removed
+    pub removed: u64,
+    /// Size of the removed files
+    pub bytes_freed: u64,
+    /// Files, or whole ticks, that failed
+    pub errors: u64,
+}
+
+/// Settings for [`FsCache::spawn_gc`].
+#[derive(Debug, Clone, Copy)]
+pub struct BackgroundGc {
+    interval: Duration,
+    budget: usize,
+}
+
+const DEFAULT_BUDGET: usize = 1000;
+
+impl BackgroundGc {
+    /// Runs a tick every `interval`.
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            budget: DEFAULT_BUDGET,
+        }
+    }
+
+    /// Caps the number of files looked at per tick, so a large directory is
+    /// collected over several ticks instead of in one long scan. Defaults to
+    /// 1000.
+    pub fn budget(mut self, budget: usize) -> Self {
+        self.budget = budget.max(1);
+        self
+    }
+}
+
+/// Controls a collector started with [`FsCache::spawn_gc`]. Dropping the
+/// handle stops the collector.
+pub struct GcHandle {
+    last_run: Arc<Mutex<Option<GcStats>>>,
+    collector: Collector,
+}
+
+enum Collector {
+    #[cfg(feature = "tokio")]
+    Task(tokio::task::JoinHandle<()>),
+    // Dropping the sender wakes the thread up and tells it to stop
+    Thread {
+        stop: Option<mpsc::Sender<()>>,
+        thread: Option<thread::JoinHandle<()>>,
+    },
+}
+
+impl GcHandle {
+    /// Statistics of the last complete pass, if one has finished yet.
+    pub fn last_run(&self) -> Option<GcStats> {
+        *self.last_run.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Stops the collector, same as dropping the handle. A tick that already
+    /// started is finished first.
+    pub fn cancel(self) {}
+}
+
+impl Drop for GcHandle {
+    fn drop(&mut self) {
+        match &mut self.collector {
+            #[cfg(feature = "tokio")]
+            Collector::Task(task) => task.abort(),
+            Collector::Thread { stop, thread } => {
+                stop.take();
+                if let Some(thread) = thread.take() {
+                    let _ = thread.join();
+                }
+            }
+        }
+    }
+}
+
+// Where a pass stopped at the end of the last tick
+#[derive(Default)]
+struct Cursor {
+    pending: Vec<Pending>,
+    stats: GcStats,
+}
+
+enum Pending {
+    // A file in the cache directory
+    File(PathBuf),
+    // A tag or prefix whose markers haven't been listed yet
+    Tag(PathBuf),
+    Marker(PathBuf),
+    // A tag whose markers were all looked at, it goes if none are left
+    TagDone(PathBuf),
+}
+
+impl<C> Inner<C> {
+    // Collects up to `budget` files of the current pass,
+    // returning its stats once the pass is done
+    fn gc_tick(&self, cursor: &mut Cursor, budget: usize) -> Option<GcStats> {
+        match self.locks.gc() {
+            Ok(Some(_lock)) => self.gc_files(cursor, budget),
+            // Another process is collecting, the pass continues next tick
+            Ok(None) => None,
+            Err(_) => {
+                cursor.stats.errors += 1;
+                None
+            }
+        }
+    }
+
+    fn gc_files(&self, cursor: &mut Cursor, budget: usize) -> Option<GcStats> {
+        let (pending, stats) = (&mut cursor.pending, &mut cursor.stats);
+        if pending.is_empty() {
+            // Files created after this are left for the next pass
+            match fs::read_dir(&self.cache_dir) {
+                Ok(files) => {
+                    for file in files {
+                        match file {
+                            Ok(file) => pending.push(Pending::File(file.path())),
+                            Err(_) => stats.errors += 1,
+                        }
+                    }
+                }
+                Err(_) => stats.errors += 1,
+            }
+            for dir in self.tag_dirs() {
+                match dir {
+                    Ok(dir) => pending.push(Pending::Tag(dir)),
+                    Err(_) => stats.errors += 1,
+                }
+            }
+        }
+
+        for _ in 0..budget {
+            let Some(next) = pending.pop() else {
+                break;
+            };
+            let collected = match next {
+                Pending::File(path) => self.collect_file(&path),
+                Pending::Marker(path) => self.collect_marker(&path),
+                Pending::Tag(dir) => {
+                    // The markers go on top, so they are all looked at before the tag is
+                    match fs::read_dir(&dir) {
+                        Ok(markers) => {
+                            pending.push(Pending::TagDone(dir));
+                            for marker in markers {
+                                match marker {
+                                    Ok(marker) => pending.push(Pending::Marker(marker.path())),
+                                    Err(_) => stats.errors += 1,
+                                }
+                            }
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                        Err(_) => stats.errors += 1,
+                    }
+                    continue;
+                }
+                Pending::TagDone(dir) => {
+                    // Fails if the tag was set again in the meantime, which is fine
+                    let _ = fs::remove_dir(&dir);
+                    continue;
+                }
+            };
+            stats.scanned += 1;
+            match collected {
+                Ok(Some(size)) => {
+                    stats.removed += 1;
+                    stats.bytes_freed += size;
+                }
+                Ok(None) => {}
+                Err(_) => stats.errors += 1,
+            }
+        }
+
+        pending.is_empty().then(|| mem::take(stats))
+    }
+}
+
+impl<C: Codec + 'static> FsCache<C> {
+    /// Starts collecting garbage in the background, a few files at a time.
+    /// Like `collect_garbage`, each pass also prunes the tag index.
+    ///
+    /// With the `tokio` feature and a runtime to spawn on, the collector is a
+    /// tokio task. Otherwise it gets its own thread. It keeps running until
+    /// the returned handle is dropped, even if the cache itself is dropped
+    /// before.
+    pub fn spawn_gc(&self, gc: BackgroundGc) -> GcHandle {
+        let inner = Arc::clone(&self.inner);
+        let last_run = Arc::new(Mutex::new(None));
+        let record = {
+            let last_run = Arc::clone(&last_run);
+            move |stats| *last_run.lock().unwrap_or_else(PoisonError::into_inner) = Some(stats)
+        };
+
+        #[cfg(feature = "tokio")]
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let task = runtime.spawn(async move {
+                let mut cursor = Cursor::default();
+                loop {
+                    tokio::time::sleep(gc.interval).await;
+                    let inner = Arc::clone(&inner);
+                    let tick = tokio::task::spawn_blocking(move || {
+                        let stats = inner.gc_tick(&mut cursor, gc.budget);
+                        (cursor, stats)
+                    });
+                    // The tick panicked or the runtime is shutting down
+                    let Ok((next, stats)) = tick.await else {
+                        break;
+                    };
+                    cursor = next;
+                    if let Some(stats) = stats {
+                        record(stats);
+                    }
+                }
+            });
+            return GcHandle {
+                last_run,
+                collector: Collector::Task(task),
+            };
+        }
+
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::spawn(move || {
+            let mut cursor = Cursor::default();
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(gc.interval) {
+                if let Some(stats) = inner.gc_tick(&mut cursor, gc.budget) {
+                    record(stats);
+                }
+            }
+        });
+        GcHandle {
+            last_run,
+            collector: Collector::Thread {
+                stop: Some(stop),
+                thread: Some(thread),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::Instant};
+
+    use super::*;
+    use crate::{
+        simple_cache::BlockingCache,
+        test_util::{block_on, TempDir},
+    };
+
+    // Fills a cache with 6 live and 4 expired entries
+    fn cache_with_garbage(dir: &TempDir) -> FsCache {
+        let cache = FsCache::new(dir.path().to_path_buf()).unwrap();
+        for i in 0..10 {
+            let expiry = if i % 3 == 0 {
+                Some(Duration::ZERO)
+            } else {
+                None
+            };
+            cache.set(i, i, expiry).unwrap();
+        }
+        cache
+    }
+
+    fn dir_stats(dir: &TempDir) -> (u64, u64) {
+        fs::read_dir(dir.path())
+            .unwrap()
+            .fold((0, 0), |(n, bytes), file| {
+                (n + 1, bytes + file.unwrap().metadata().unwrap().len())
+            })
+    }
+
+    fn wait_for_pass(handle: &GcHandle) -> GcStats {
+        let start = Instant::now();
+        loop {
+            if let Some(stats) = handle.last_run() {
+                return stats;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5), "no pass finished");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn passes_are_split_into_ticks_of_the_budget() {
+        let dir = TempDir::new();
+        let cache = cache_with_garbage(&dir);
+        let (files, bytes) = dir_stats(&dir);
+
+        let mut cursor = Cursor::default();
+        let mut ticks = 1;
+        let stats = loop {
+            if let Some(stats) = cache.inner.gc_tick(&mut cursor, 3) {
+                break stats;
+            }
+            assert_eq!(cursor.stats.scanned, ticks * 3);
+            ticks += 1;
+        };
+        assert_eq!(ticks, files.div_ceil(3));
+
+        let (_, bytes_left) = dir_stats(&dir);
+        assert_eq!(
+            stats,
+            GcStats {
+                scanned: files,
+                removed: 4,
+                bytes_freed: bytes - bytes_left,
+                errors: 0,
+            }
+        );
+        for i in 0..10 {
+            let expected = (i % 3 != 0).then_some(i);
+            assert_eq!(cache.get::<i32>(i).unwrap(), expected);
+        }
+
+        // The next pass starts over with fresh stats
+        let stats = cache.inner.gc_tick(&mut cursor, usize::MAX).unwrap();
+        assert_eq!(stats.removed, 0);
+        assert_eq!(stats.scanned, files - 4);
+    }
+
+    #[test]
+    fn passes_prune_the_tag_index() {
+        let dir = TempDir::new();
+        let cache = FsCache::new(dir.path().to_path_buf()).unwrap();
+        block_on(cache.set_with_tags("expired", 1, Duration::ZERO, ["t"])).unwrap();
+        block_on(cache.set_with_tags("live", 1, None, ["t"])).unwrap();
+        block_on(cache.set_with_tags("gone", 1, None, ["u"])).unwrap();
+        cache.invalidate("gone").unwrap();
+        let markers = |tag: &Path| fs::read_dir(tag).map_or(0, |markers| markers.count());
+        let tags: Vec<_> = fs::read_dir(dir.join("tags"))
+            .unwrap()
+            .map(|tag| tag.unwrap().path())
+            .collect();
+        assert_eq!(tags.iter().map(|tag| markers(tag)).sum::<usize>(), 3);
+
+        let mut cursor = Cursor::default();
+        let stats = loop {
+            if let Some(stats) = cache.inner.gc_tick(&mut cursor, 2) {
+                break stats;
+            }
+        };
+        // The expired entry and the markers of both entries that are gone
+        assert_eq!(stats.removed, 3);
+        assert_eq!(stats.errors, 0);
+        let left: Vec<_> = tags.iter().map(|tag| markers(tag)).collect();
+        assert!(left == [0, 1] || left == [1, 0], "{left:?}");
+        assert_eq!(fs::read_dir(dir.join("tags")).unwrap().count(), 1);
+
+        block_on(cache.invalidate_tag("t")).unwrap();
+        assert_eq!(cache.get::<i32>("live").unwrap(), None);
+    }
+
+    #[test]
+    fn background_passes_report_their_stats() {
+        let dir = TempDir::new();
+        let cache = cache_with_garbage(&dir);
+        let (files, _) = dir_stats(&dir);
+
+        let gc = BackgroundGc::new(Duration::from_millis(5)).budget(2);
+        let handle = cache.spawn_gc(gc);
+        let stats = wait_for_pass(&handle);
+        assert_eq!(stats.scanned, files);
+        assert_eq!(stats.removed, 4);
+        assert!(stats.bytes_freed > 0);
+        assert_eq!(stats.errors, 0);
+    }
+
+    // Expired entries written after the collector stopped have to stay
+    fn check_stopped(dir: &TempDir, cache: &FsCache) {
+        let (files, _) = dir_stats(dir);
+        cache.set("late", 1, Duration::ZERO).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(dir_stats(dir).0, files + 1);
+    }
+
+    #[test]
+    fn dropping_the_handle_stops_the_collector() {
+        let dir = TempDir::new();
+        let cache = cache_with_garbage(&dir);
+        let handle = cache.spawn_gc(BackgroundGc::new(Duration::from_millis(5)));
+        wait_for_pass(&handle);
+        drop(handle);
+        check_stopped(&dir, &cache);
+    }
+
+    #[test]
+    fn cancelling_stops_the_collector() {
+        let dir = TempDir::new();
+        let cache = cache_with_garbage(&dir);
+        let handle = cache.spawn_gc(BackgroundGc::new(Duration::from_millis(5)));
+        wait_for_pass(&handle);
+        handle.cancel();
+        check_stopped(&dir, &cache);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn cancelling_stops_the_collector_task() {
+        let dir = TempDir::new();
+        let cache = cache_with_garbage(&dir);
+        let (files, _) = dir_stats(&dir);
+        let handle = cache.spawn_gc(BackgroundGc::new(Duration::from_millis(5)));
+        while handle.last_run().is_none() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        handle.cancel();
+
+        crate::simple_cache::SendCache::set(&cache, "late", 1, Duration::ZERO)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(dir_stats(&dir).0, files - 4 + 1);
+    }
+}
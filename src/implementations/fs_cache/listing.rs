@@ -0,0 +1,263 @@
+use chrono::DateTime;
+use futures_core::Stream;
+use std::{
+    collections::VecDeque,
+    fs::{self, File, ReadDir},
+    io, mem,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+    time::SystemTime,
+};
+
+use super::{entry, offload, parse_slot_name, FsCache};
+use crate::{
+    dyn_cache::BoxFuture, error::CacheError, key_hasher::EncodedKey, simple_cache::EntryInfo,
+};
+
+// Files looked at per trip to the blocking thread pool
+const CHUNK_LEN: usize = 256;
+
+type Chunk = Vec<Result<EntryInfo, CacheError>>;
+
+/// The entries of an `FsCache` that haven't expired, see [`FsCache::entries`].
+pub struct Entries {
+    state: State,
+    listed: VecDeque<Result<EntryInfo, CacheError>>,
+}
+
+/// The keys of an `FsCache`'s entries that haven't expired, see
+/// [`FsCache::keys`].
+pub struct Keys(Entries);
+
+enum State {
+    Idle(Lister),
+    Listing(BoxFuture<'static, Result<(Lister, Chunk), CacheError>>),
+    Done,
+}
+
+struct Lister {
+    cache_dir: PathBuf,
+    // Opened by the first chunk, so nothing blocks before the stream is polled
+    files: Option<ReadDir>,
+    exhausted: bool,
+}
+
+impl<C> FsCache<C> {
+    /// Lists the entries that haven't expired, in no particular order.
+    ///
+    /// The directory is read bit by bit as the stream is polled, so entries
+    /// set or removed in the meantime may or may not show up.
+    pub fn entries(&self) -> Entries {
+        Entries {
+            state: State::Idle(Lister {
+                cache_dir: self.inner.cache_dir.clone(),
+                files: None,
+                exhausted: false,
+            }),
+            listed: VecDeque::new(),
+        }
+    }
+
+    /// Lists the keys of the entries that haven't expired, like
+    /// [`entries`](Self::entries).
+    pub fn keys(&self) -> Keys {
+        Keys(self.entries())
+    }
+}
+
+impl Lister {
+    fn next_chunk(&mut self) -> Result<Chunk, CacheError> {
+        let files = match &mut self.files {
+            Some(files) => files,
+            None => self.files.insert(fs::read_dir(&self.cache_dir)?),
+        };
+
+        let mut chunk = Vec::new();
+        for _ in 0..CHUNK_LEN {
+            let Some(file) = files.next() else {
+                self.exhausted = true;
+                break;
+            };
+            match file
+                .map_err(CacheError::from)
+                .and_then(|file| read_info(&file.path()))
+            {
+                Ok(Some(info)) => chunk.push(Ok(info)),
+                Ok(None) => {}
+                Err(e) => chunk.push(Err(e)),
+            }
+        }
+        Ok(chunk)
+    }
+}
+
+// Returns `None` for anything that isn't a live entry
+fn read_info(path: &Path) -> Result<Option<EntryInfo>, CacheError> {
+    if parse_slot_name(path).is_none() {
+        return Ok(None);
+    }
+
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let size = file.metadata()?.len();
+    let header = match entry::read_header(&mut file) {
+        Ok(header) => header,
+        // Garbage, like expired entries
+        Err(CacheError::Corrupt(_)) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if header.is_expired() {
+        return Ok(None);
+    }
+
+    let system_time = |millis| {
+        DateTime::from_timestamp_millis(millis).map_or(SystemTime::UNIX_EPOCH, SystemTime::from)
+    };
+    Ok(Some(EntryInfo {
+        size,
+        created_at: system_time(header.created_at),
+        expires_at: header.deadline().map(system_time),
+        key: EncodedKey::from_bytes(header.key),
+    }))
+}
+
+impl Stream for Entries {
+    type Item = Result<EntryInfo, CacheError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(item) = this.listed.pop_front() {
+                return Poll::Ready(Some(item));
+            }
+
+            match mem::replace(&mut this.state, State::Done) {
+                State::Idle(lister) if lister.exhausted => return Poll::Ready(None),
+                State::Idle(mut lister) => {
+                    this.state = State::Listing(Box::pin(offload(move || {
+                        let chunk = lister.next_chunk()?;
+                        Ok((lister, chunk))
+                    })));
+                }
+                State::Listing(mut listing) => match listing.as_mut().poll(cx) {
+                    Poll::Pending => {
+                        this.state = State::Listing(listing);
+                        return Poll::Pending;
+                    }
+                    Poll::Ready(Ok((lister, chunk))) => {
+                        this.listed.extend(chunk);
+                        this.state = State::Idle(lister);
+                    }
+                    // The directory can't be read at all
+                    Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+                },
+                State::Done => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl Stream for Keys {
+    type Item = Result<EncodedKey, CacheError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.0)
+            .poll_next(cx)
+            .map(|item| item.map(|info| info.map(|info| info.key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, time::Duration};
+
+    use super::*;
+    use crate::{
+        simple_cache::BlockingCache,
+        test_util::{block_on, collect, TempDir},
+        ExpiryPolicy,
+    };
+
+    fn listed(cache: &FsCache) -> Vec<EntryInfo> {
+        let mut entries: Vec<_> = block_on(collect(cache.entries()))
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        entries
+    }
+
+    #[test]
+    fn lists_only_live_entries() {
+        let dir = TempDir::new();
+        let cache = FsCache::new(dir.path().to_path_buf()).unwrap();
+        let hour = Duration::from_secs(60 * 60);
+        let before = SystemTime::now() - Duration::from_millis(1);
+        cache.set("never", 1, None).unwrap();
+        cache.set("ttl", 2, hour).unwrap();
+        cache.set("tti", 3, ExpiryPolicy::tti(hour)).unwrap();
+        let after = SystemTime::now();
+
+        // None of these are live entries
+        cache.set("expired", 4, Duration::ZERO).unwrap();
+        fs::write(dir.join("12345"), b"not an entry").unwrap();
+        block_on(cache.set_with_tags("tagged", 5, Duration::ZERO, ["tag"])).unwrap();
+        let namespace = cache.namespace("ns").build().unwrap();
+        namespace.set("namespaced", 6, None).unwrap();
+        assert!(dir.join("tags").is_dir() && dir.join("namespaces").is_dir());
+
+        let entries = listed(&cache);
+        let keys: Vec<_> = ["never", "tti", "ttl"].map(EncodedKey::new).into();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|info| info.key.clone())
+                .collect::<Vec<_>>(),
+            keys
+        );
+        for info in &entries {
+            let hash = cache.inner.key_hasher.hash(info.key.as_bytes());
+            let path = cache.inner.slot_path(hash, 0);
+            assert_eq!(info.size, fs::metadata(path).unwrap().len());
+            assert!(before <= info.created_at && info.created_at <= after);
+        }
+        let [never, tti, ttl] = &entries[..] else {
+            unreachable!()
+        };
+        assert_eq!(never.expires_at, None);
+        for info in [tti, ttl] {
+            let expires_at = info.expires_at.unwrap();
+            assert!(before + hour <= expires_at && expires_at <= after + hour);
+        }
+
+        let mut listed: Vec<_> = block_on(collect(cache.keys()))
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        listed.sort();
+        assert_eq!(listed, keys);
+    }
+
+    #[tokio::test]
+    async fn lists_directories_larger_than_a_chunk() {
+        let dir = TempDir::new();
+        let cache = FsCache::new(dir.path().to_path_buf()).unwrap();
+        let len = CHUNK_LEN * 2 + 10;
+        for i in 0..len {
+            crate::simple_cache::SendCache::set(&cache, i, i, None)
+                .await
+                .unwrap();
+        }
+
+        let keys: HashSet<_> = collect(cache.keys())
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(keys, (0..len).map(EncodedKey::new).collect());
+    }
+}
@@ -0,0 +1,185 @@
+use std::{
+    fs::{self, File, OpenOptions, TryLockError},
+    io,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::error::CacheError;
+
+// Lock files live in their own subdirectory so they are never mistaken for
+// entries. They are never removed, removing a lock file someone is waiting on
+// would let two processes hold "the same" lock
+pub(crate) const LOCK_DIR: &str = ".locks";
+
+// Entries are locked by hash, spread over this many lock files.
+// Keys sharing a stripe only contend, they never deadlock
+const STRIPES: u64 = 64;
+
+const GC_LOCK: &str = "gc";
+
+// Longest pause between two attempts to take a contended lock
+const MAX_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Advisory locks that keep processes sharing a cache directory from
+/// undoing each other's changes.
+///
+/// Each hash has an exclusive lock covering every slot of its chain, held for
+/// the whole lookup and write or removal. Reads don't lock, since entries are
+/// replaced atomically. The directory-wide garbage collection lock keeps two
+/// garbage collection or eviction passes from running at once.
+pub(crate) struct Locks {
+    dir: PathBuf,
+    timeout: Duration,
+}
+
+/// A held lock, released when dropped.
+pub(crate) struct LockGuard {
+    _file: File,
+}
+
+impl Locks {
+    pub fn new(cache_dir: &Path, timeout: Duration) -> Result<Self, io::Error> {
+        let dir = cache_dir.join(LOCK_DIR);
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir, timeout })
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Locks the chain of entries for `hash`, waiting up to the timeout.
+    pub fn entry(&self, hash: u64) -> Result<LockGuard, CacheError> {
+        self.wait(self.open(&(hash % STRIPES).to_string())?)
+    }
+
+    /// Takes the garbage collection lock, or returns `None` if another pass
+    /// holds it.
+    pub fn gc(&self) -> Result<Option<LockGuard>, CacheError> {
+        let file = self.open(GC_LOCK)?;
+        match file.try_lock() {
+            Ok(()) => Ok(Some(LockGuard { _file: file })),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+
+    /// Takes the garbage collection lock, waiting up to the timeout.
+    pub fn gc_wait(&self) -> Result<LockGuard, CacheError> {
+        self.wait(self.open(GC_LOCK)?)
+    }
+
+    fn wait(&self, file: File) -> Result<LockGuard, CacheError> {
+        let deadline = Instant::now() + self.timeout;
+        let mut backoff = Duration::from_millis(1);
+        loop {
+            match file.try_lock() {
+                Ok(()) => return Ok(LockGuard { _file: file }),
+                Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+                Err(TryLockError::WouldBlock) => return Err(CacheError::Timeout),
+                Err(TryLockError::Error(e)) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn open(&self, name: &str) -> Result<File, io::Error> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.dir.join(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        process::{Command, Stdio},
+        time::Duration,
+    };
+
+    use crate::{simple_cache::BlockingCache, test_util::TempDir, FsCache};
+
+    // Set in the child processes of `processes_share_a_directory`
+    const CHILD_DIR: &str = "SIMPLE_CACHE_TEST_CHILD_DIR";
+    const CHILD_ID: &str = "SIMPLE_CACHE_TEST_CHILD_ID";
+
+    const CHILDREN: u32 = 4;
+    const ROUNDS: u32 = 200;
+
+    // What each child does to the shared directory
+    fn hammer(dir: String, id: u32) {
+        let cache = FsCache::new(dir.into()).unwrap();
+        for round in 0..ROUNDS {
+            // Every child writes these
+            let shared = round % 8;
+            cache.set(shared, (id, round), None).unwrap();
+            assert!(cache.get::<(u32, u32)>(shared).unwrap().is_some());
+
+            // Expired the moment they are written, for garbage collection to find
+            cache
+                .set(("expired", shared), round, Duration::ZERO)
+                .unwrap();
+
+            // Only this child writes these, none of them may get lost
+            cache.set((id, round), round, None).unwrap();
+            assert_eq!(cache.get((id, round)).unwrap(), Some(round));
+
+            if round % 10 == 0 {
+                cache.collect_garbage().unwrap();
+                cache.invalidate(("expired", shared)).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn processes_share_a_directory() {
+        if let (Ok(dir), Ok(id)) = (env::var(CHILD_DIR), env::var(CHILD_ID)) {
+            return hammer(dir, id.parse().unwrap());
+        }
+
+        let dir = TempDir::new();
+        let children: Vec<_> = (0..CHILDREN)
+            .map(|id| {
+                Command::new(env::current_exe().unwrap())
+                    .args([
+                        "--exact",
+                        "implementations::fs_cache::lock::tests::processes_share_a_directory",
+                    ])
+                    .env(CHILD_DIR, dir.path())
+                    .env(CHILD_ID, id.to_string())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::piped())
+                    .spawn()
+                    .unwrap()
+            })
+            .collect();
+        for child in children {
+            let output = child.wait_with_output().unwrap();
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+
+        let cache = FsCache::new(dir.path().to_path_buf()).unwrap();
+        cache.collect_garbage().unwrap();
+        for id in 0..CHILDREN {
+            for round in 0..ROUNDS {
+                assert_eq!(cache.get((id, round)).unwrap(), Some(round));
+            }
+        }
+        for shared in 0..8 {
+            assert!(cache.get::<(u32, u32)>(shared).unwrap().is_some());
+            assert_eq!(cache.get::<u32>(("expired", shared)).unwrap(), None);
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use serde::{Deserialize, Serialize};
+use std::{fs, io, path::Path};
+
+use super::{
+    atomic_write::{self, FsyncPolicy},
+    lock::LOCK_DIR,
+};
+use crate::{
+    error::CacheError,
+    key_hasher::{key_encoding_fingerprint, KeyHasher},
+};
+
+pub(crate) const MANIFEST_FILE: &str = "manifest.json";
+
+// Bump whenever the way entries are named changes, or their format changes
+// in a way older versions of the entry format (see `entry.rs`) cannot handle
+pub(crate) const LAYOUT_VERSION: u32 = 5;
+
+/// What `FsCache` does when the cache directory was written with a different
+/// layout or key hasher than the one it is opened with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ManifestMismatch {
+    /// Fail to open the cache
+    #[default]
+    Refuse,
+    /// Remove every entry and start over with a fresh manifest
+    Reset,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    pub layout: u32,
+    pub key_hasher: String,
+    // See `key_encoding_fingerprint`. Empty in manifests written before it was recorded
+    #[serde(default)]
+    pub key_encoding: String,
+}
+
+impl Manifest {
+    pub fn new(key_hasher: &dyn KeyHasher) -> Self {
+        Self {
+            layout: LAYOUT_VERSION,
+            key_hasher: key_hasher.id().to_string(),
+            key_encoding: key_encoding_fingerprint(),
+        }
+    }
+
+    // Same layout and key hasher, from before key encodings were recorded.
+    // The encoding can't be checked, so it is assumed to be the current one
+    fn predates_key_encoding(&self, expected: &Manifest) -> bool {
+        self.key_encoding.is_empty()
+            && self.layout == expected.layout
+            && self.key_hasher == expected.key_hasher
+    }
+}
+
+/// Makes sure `cache_dir` was written by a compatible `FsCache`, writing a
+/// manifest if the directory is new.
+pub(crate) fn check(
+    cache_dir: &Path,
+    key_hasher: &dyn KeyHasher,
+    on_mismatch: ManifestMismatch,
+) -> Result<(), CacheError> {
+    let expected = Manifest::new(key_hasher);
+    let manifest_path = cache_dir.join(MANIFEST_FILE);
+
+    let mut found = read(&manifest_path)?;
+    // A directory without a manifest is only compatible if it is empty,
+    // anything else predates manifests and used an unstable hasher.
+    // Another process may have written its manifest and then entries since
+    // we looked, so look again before calling the directory incompatible
+    let empty = found.is_none() && is_empty(cache_dir)?;
+    if found.is_none() && !empty {
+        found = read(&manifest_path)?;
+    }
+
+    match found {
+        Some(found) if found == expected => return Ok(()),
+        Some(found) if found.predates_key_encoding(&expected) => {}
+        None if empty => {}
+        found => {
+            if on_mismatch == ManifestMismatch::Refuse {
+                let found = match found {
+                    Some(found) => {
+                        format!(
+                            "layout {} with key hasher {} and key encoding {}",
+                            found.layout, found.key_hasher, found.key_encoding
+                        )
+                    }
+                    None => "no manifest".to_string(),
+                };
+                return Err(CacheError::Incompatible(format!(
+                    "cache directory {} has {found}, expected layout {} with key hasher {} \
+                     and key encoding {}",
+                    cache_dir.display(),
+                    expected.layout,
+                    expected.key_hasher,
+                    expected.key_encoding
+                )));
+            }
+            clear(cache_dir)?;
+        }
+    }
+
+    let manifest = serde_json::to_vec(&expected).map_err(|e| CacheError::Serialize(e.into()))?;
+    // Other processes may be reading it right now
+    atomic_write::write(&manifest_path, &manifest, FsyncPolicy::Never)?;
+    Ok(())
+}
+
+fn read(manifest_path: &Path) -> Result<Option<Manifest>, CacheError> {
+    match fs::read(manifest_path) {
+        Ok(manifest) => {
+            Ok(Some(serde_json::from_slice(&manifest).map_err(|e| {
+                CacheError::corrupt(format!("unreadable manifest: {e}"))
+            })?))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Lock files don't count, other processes may be waiting on them.
+// Neither do manifests being written by another process that is opening
+// the directory at the same time
+fn is_empty(cache_dir: &Path) -> Result<bool, io::Error> {
+    for file in fs::read_dir(cache_dir)? {
+        let file = file?;
+        if file.file_name() != LOCK_DIR && !atomic_write::is_temporary(&file.path()) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn clear(cache_dir: &Path) -> Result<(), io::Error> {
+    for file in fs::read_dir(cache_dir)? {
+        let file = file?;
+        if file.file_name() == LOCK_DIR {
+            continue;
+        }
+        let path = file.path();
+        if path.is_dir() {
+            fs::remove_dir_all(path)?;
+        } else {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{key_hasher::SipKeyHasher, test_util::TempDir};
+
+    fn write_manifest(dir: &TempDir, manifest: &str) {
+        fs::write(dir.join(MANIFEST_FILE), manifest).unwrap();
+        // Something for `Reset` to remove
+        fs::write(dir.join("1"), "entry").unwrap();
+    }
+
+    fn read_manifest(dir: &TempDir) -> Manifest {
+        serde_json::from_slice(&fs::read(dir.join(MANIFEST_FILE)).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn writes_a_manifest_for_new_directories() {
+        let dir = TempDir::new();
+        check(dir.path(), &SipKeyHasher, ManifestMismatch::Refuse).unwrap();
+        assert_eq!(read_manifest(&dir), Manifest::new(&SipKeyHasher));
+        check(dir.path(), &SipKeyHasher, ManifestMismatch::Refuse).unwrap();
+    }
+
+    #[test]
+    fn refuses_another_key_encoding() {
+        let dir = TempDir::new();
+        write_manifest(
+            &dir,
+            &format!(
+                r#"{{"layout":{LAYOUT_VERSION},"key_hasher":"siphash13-v1","key_encoding":"0"}}"#
+            ),
+        );
+        let res = check(dir.path(), &SipKeyHasher, ManifestMismatch::Refuse);
+        assert!(matches!(res, Err(CacheError::Incompatible(_))));
+
+        check(dir.path(), &SipKeyHasher, ManifestMismatch::Reset).unwrap();
+        assert!(!dir.join("1").exists());
+        assert_eq!(read_manifest(&dir), Manifest::new(&SipKeyHasher));
+    }
+
+    #[test]
+    fn accepts_manifests_without_key_encoding() {
+        let dir = TempDir::new();
+        write_manifest(
+            &dir,
+            &format!(r#"{{"layout":{LAYOUT_VERSION},"key_hasher":"siphash13-v1"}}"#),
+        );
+        check(dir.path(), &SipKeyHasher, ManifestMismatch::Refuse).unwrap();
+        assert!(dir.join("1").exists());
+        assert_eq!(read_manifest(&dir), Manifest::new(&SipKeyHasher));
+    }
+
+    #[test]
+    fn refuses_entries_without_manifest() {
+        let dir = TempDir::new();
+        fs::write(dir.join("1"), "entry").unwrap();
+        let res = check(dir.path(), &SipKeyHasher, ManifestMismatch::Refuse);
+        assert!(matches!(res, Err(CacheError::Incompatible(_))));
+    }
+
+    #[test]
+    fn ignores_manifests_being_written_by_others() {
+        let dir = TempDir::new();
+        // What another process opening the directory leaves until it renames
+        fs::write(dir.join(".tmp-1-0"), "{").unwrap();
+        check(dir.path(), &SipKeyHasher, ManifestMismatch::Refuse).unwrap();
+        assert_eq!(read_manifest(&dir), Manifest::new(&SipKeyHasher));
+    }
+}
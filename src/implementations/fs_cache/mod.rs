@@ -0,0 +1,1039 @@
+mod atomic_write;
+mod batch;
+mod entry;
+mod eviction;
+mod gc;
+mod listing;
+mod lock;
+mod manifest;
+mod namespace;
+mod tags;
+
+use chrono::DateTime;
+use std::{
+    cmp::Reverse,
+    fs::{self, File, OpenOptions},
+    future::Future,
+    hash::Hash,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+pub use atomic_write::FsyncPolicy;
+pub use eviction::EvictionPolicy;
+pub use gc::{BackgroundGc, GcHandle, GcStats};
+pub use listing::{Entries, Keys};
+pub use manifest::ManifestMismatch;
+
+use self::{entry::Header, eviction::Limits, lock::Locks, tags::Tag};
+use crate::{
+    codec::{Codec, Json},
+    error::CacheError,
+    expiry::ExpiryPolicy,
+    key_hasher::{encode_key, EncodedKey, KeyHasher, SipKeyHasher},
+    simple_cache::{BlockingCache, RawEntry, SendCache},
+    single_flight::{self, SingleFlight},
+};
+
+/// A cache storing each entry in its own file under a directory.
+///
+/// Several processes can share a directory: changes to an entry and garbage
+/// collection are serialized with advisory file locks.
+///
+/// Filesystem calls block, so with the `tokio` feature enabled they are run
+/// on tokio's blocking thread pool and the cache must be used from within a
+/// tokio runtime. Without it they run on the calling task.
+pub struct FsCache<C = Json> {
+    inner: Arc<Inner<C>>,
+    flights: SingleFlight,
+}
+
+// Shared with the blocking tasks filesystem work is offloaded to
+struct Inner<C> {
+    cache_dir: PathBuf,
+    codec: C,
+    key_hasher: Arc<dyn KeyHasher + Send + Sync>,
+    chain_collisions: bool,
+    fsync: FsyncPolicy,
+    locks: Locks,
+    limits: Option<Limits>,
+    default_expiry: ExpiryPolicy,
+}
+
+// Where a key lives, or would live, in the cache directory
+enum Slot {
+    // Holds an entry for the key, the reader is positioned at its value
+    Occupied(PathBuf, File, Header),
+    // Holds an entry for a different key with the same hash
+    Taken(PathBuf),
+    // Holds an entry that can't be read, so it may be overwritten
+    Corrupt(PathBuf, CacheError),
+    Vacant(PathBuf),
+    // Every slot the key may use holds a different key
+    Full,
+}
+
+// Longest chain of keys sharing a hash, any more fail with `KeyCollision`
+const MAX_CHAIN_LEN: usize = 16;
+
+const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl FsCache {
+    pub fn new(cache_dir: PathBuf) -> Result<Self, CacheError> {
+        Self::builder(cache_dir).build()
+    }
+
+    pub fn builder(cache_dir: PathBuf) -> FsCacheBuilder {
+        FsCacheBuilder {
+            cache_dir,
+            codec: Json,
+            key_hasher: Arc::new(SipKeyHasher),
+            on_mismatch: ManifestMismatch::default(),
+            chain_collisions: false,
+            fsync: FsyncPolicy::default(),
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+            max_bytes: None,
+            max_entries: None,
+            eviction_policy: EvictionPolicy::default(),
+            default_expiry: ExpiryPolicy::NEVER,
+        }
+    }
+}
+
+impl<C> Inner<C> {
+    // Converts the hash of the key to a string
+    // and appends it to the cache directory
+    // to create a unique file path.
+    // Colliding keys are chained into `<hash>-1`, `<hash>-2`, ...
+    fn slot_path(&self, hash: u64, slot: usize) -> PathBuf {
+        if slot == 0 {
+            self.cache_dir.join(hash.to_string())
+        } else {
+            self.cache_dir.join(format!("{hash}-{slot}"))
+        }
+    }
+
+    // Walks the chain for `key` until it finds the key or a free slot.
+    // Without chaining only the first slot is considered
+    fn lookup(&self, key: &[u8]) -> Result<Slot, CacheError> {
+        let hash = self.key_hasher.hash(key);
+        for slot in 0..MAX_CHAIN_LEN {
+            let path = self.slot_path(hash, slot);
+            let mut file = match self.open_entry(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Slot::Vacant(path)),
+                Err(e) => return Err(e.into()),
+            };
+            let header = match entry::read_header(&mut file) {
+                Ok(header) => header,
+                Err(e @ CacheError::Corrupt(_)) => return Ok(Slot::Corrupt(path, e)),
+                Err(e) => return Err(e),
+            };
+            if header.key == key {
+                return Ok(Slot::Occupied(path, file, header));
+            }
+            if !self.chain_collisions {
+                return Ok(Slot::Taken(path));
+            }
+        }
+        Ok(Slot::Full)
+    }
+
+    // Opens an entry for reading, and for recording reads where possible
+    fn open_entry(&self, path: &Path) -> Result<File, io::Error> {
+        match OpenOptions::new().read(true).write(true).open(path) {
+            // Reads still work in a directory we may not write to, they just go unrecorded
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem
+                ) =>
+            {
+                File::open(path)
+            }
+            res => res,
+        }
+    }
+
+    // Removes the entry at `path`, moving the last entry of its chain
+    // into the hole so lookups can keep stopping at the first free slot.
+    // An entry that is already gone counts as removed
+    fn remove_slot(&self, path: &Path) -> Result<(), io::Error> {
+        match self.last_in_chain(path) {
+            Some(last) if last != path => match fs::rename(&last, path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            },
+            _ => remove_if_exists(path)?,
+        }
+        if self.fsync == FsyncPolicy::FileAndDir {
+            atomic_write::sync_dir(&self.cache_dir)?;
+        }
+        Ok(())
+    }
+
+    // Removes `path` if it is an expired or unreadable entry,
+    // or a temporary file left behind by a write that never finished
+    // Returns the size of the file if it was removed
+    fn collect_file(&self, path: &Path) -> Result<Option<u64>, CacheError> {
+        let Some((hash, _)) = parse_slot_name(path) else {
+            if !atomic_write::is_abandoned(path)? {
+                return Ok(None);
+            }
+            let size = fs::metadata(path).map_or(0, |metadata| metadata.len());
+            remove_if_exists(path)?;
+            return Ok(Some(size));
+        };
+
+        // A writer may replace the entry between our check and the removal otherwise
+        let _lock = self.locks.entry(hash)?;
+        // Compacting a chain may have moved the entry away since the directory was read
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let is_garbage = match entry::read_header(&mut file) {
+            Ok(header) => header.is_expired(),
+            // Nothing can ever read a corrupt entry, so it is garbage too
+            Err(CacheError::Corrupt(_)) => true,
+            Err(e) => return Err(e),
+        };
+        if !is_garbage {
+            return Ok(None);
+        }
+        let size = file.metadata()?.len();
+        self.remove_slot(path)?;
+        Ok(Some(size))
+    }
+
+    fn last_in_chain(&self, path: &Path) -> Option<PathBuf> {
+        if !self.chain_collisions {
+            return None;
+        }
+        let (hash, slot) = parse_slot_name(path)?;
+        let mut last = path.to_path_buf();
+        for slot in slot + 1.. {
+            let next = self.slot_path(hash, slot);
+            if !next.exists() {
+                break;
+            }
+            last = next;
+        }
+        Some(last)
+    }
+}
+
+// Returns the hash and chain position encoded in an entry's file name,
+// or `None` if the file is not an entry
+fn parse_slot_name(path: &Path) -> Option<(u64, usize)> {
+    let name = path.file_name()?.to_str()?;
+    match name.split_once('-') {
+        Some((hash, slot)) => Some((hash.parse().ok()?, slot.parse().ok()?)),
+        None => Some((name.parse().ok()?, 0)),
+    }
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), io::Error> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+pub struct FsCacheBuilder<C = Json> {
+    cache_dir: PathBuf,
+    codec: C,
+    key_hasher: Arc<dyn KeyHasher + Send + Sync>,
+    on_mismatch: ManifestMismatch,
+    chain_collisions: bool,
+    fsync: FsyncPolicy,
+    lock_timeout: Duration,
+    max_bytes: Option<u64>,
+    max_entries: Option<u64>,
+    eviction_policy: EvictionPolicy,
+    default_expiry: ExpiryPolicy,
+}
+
+impl<C: Codec> FsCacheBuilder<C> {
+    /// Sets the format values are stored in. Defaults to [`Json`].
+    pub fn codec<D: Codec>(self, codec: D) -> FsCacheBuilder<D> {
+        FsCacheBuilder {
+            cache_dir: self.cache_dir,
+            codec,
+            key_hasher: self.key_hasher,
+            on_mismatch: self.on_mismatch,
+            chain_collisions: self.chain_collisions,
+            fsync: self.fsync,
+            lock_timeout: self.lock_timeout,
+            max_bytes: self.max_bytes,
+            max_entries: self.max_entries,
+            eviction_policy: self.eviction_policy,
+            default_expiry: self.default_expiry,
+        }
+    }
+
+    /// Sets the hasher used to derive file names from keys. Defaults to
+    /// [`SipKeyHasher`].
+    pub fn key_hasher(mut self, key_hasher: impl KeyHasher + Send + Sync + 'static) -> Self {
+        self.key_hasher = Arc::new(key_hasher);
+        self
+    }
+
+    /// Sets what happens when the directory was written with another layout or
+    /// key hasher. Defaults to [`ManifestMismatch::Refuse`].
+    pub fn on_mismatch(mut self, on_mismatch: ManifestMismatch) -> Self {
+        self.on_mismatch = on_mismatch;
+        self
+    }
+
+    /// Lets keys whose hashes collide coexist by chaining them into extra
+    /// files, up to 16 keys per hash. When disabled (the default) the most
+    /// recent `set` wins and the other key reads as a miss.
+    pub fn chain_collisions(mut self, chain_collisions: bool) -> Self {
+        self.chain_collisions = chain_collisions;
+        self
+    }
+
+    /// Sets how writes are synced to disk. Defaults to [`FsyncPolicy::Never`].
+    pub fn fsync(mut self, fsync: FsyncPolicy) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
+    /// Sets how long a change waits for another process working on the same
+    /// entry before failing with `CacheError::Timeout`. Defaults to 10 seconds.
+    pub fn lock_timeout(mut self, lock_timeout: Duration) -> Self {
+        self.lock_timeout = lock_timeout;
+        self
+    }
+
+    /// Caps the size of the entry files. Unbounded by default.
+    ///
+    /// Going over a limit on `set` evicts entries according to the eviction
+    /// policy until the cache is back below 90% of its limits. With several
+    /// processes sharing the directory, each only notices the others' writes
+    /// when it evicts, so the cache may briefly exceed its limits.
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Caps the number of entries. Unbounded by default. See
+    /// [`max_bytes`](Self::max_bytes) for how limits are enforced. With a cap
+    /// of 0 every `set` fails with `CacheError::CapacityExceeded`.
+    pub fn max_entries(mut self, max_entries: u64) -> Self {
+        self.max_entries = Some(max_entries);
+        self
+    }
+
+    /// Sets which entries are evicted first. Defaults to [`EvictionPolicy::Lru`].
+    /// With `Lru` and `Lfu` every read updates the entry it hits.
+    pub fn eviction_policy(mut self, eviction_policy: EvictionPolicy) -> Self {
+        self.eviction_policy = eviction_policy;
+        self
+    }
+
+    /// Sets the expiry of entries stored without one, i.e. with `None` or
+    /// [`ExpiryPolicy::DEFAULT`]. Entries stored with [`ExpiryPolicy::NEVER`]
+    /// never expire regardless. Defaults to never expiring.
+    pub fn default_expiry(mut self, default_expiry: impl Into<ExpiryPolicy>) -> Self {
+        self.default_expiry = default_expiry.into();
+        self
+    }
+
+    pub fn build(self) -> Result<FsCache<C>, CacheError> {
+        if !self.cache_dir.exists() {
+            fs::create_dir_all(&self.cache_dir)?;
+        }
+        manifest::check(&self.cache_dir, self.key_hasher.as_ref(), self.on_mismatch)?;
+        let locks = Locks::new(&self.cache_dir, self.lock_timeout)?;
+        Ok(FsCache {
+            inner: Arc::new(Inner {
+                cache_dir: self.cache_dir,
+                codec: self.codec,
+                key_hasher: self.key_hasher,
+                chain_collisions: self.chain_collisions,
+                fsync: self.fsync,
+                locks,
+                limits: Limits::new(self.max_bytes, self.max_entries, self.eviction_policy),
+                default_expiry: self.default_expiry,
+            }),
+            flights: SingleFlight::default(),
+        })
+    }
+}
+
+impl<C> Inner<C> {
+    fn read(&self, key: &[u8]) -> Result<Option<RawEntry>, CacheError> {
+        let (mut file, header) = match self.lookup(key)? {
+            Slot::Occupied(_, file, header) => (file, header),
+            Slot::Corrupt(_, e) => return Err(e),
+            Slot::Taken(_) | Slot::Vacant(_) | Slot::Full => return Ok(None),
+        };
+
+        // Check if the expiry time has passed
+        if header.is_expired() {
+            return Ok(None);
+        }
+
+        let value = entry::read_payload(&mut file, &header)?;
+        // Reads matter to entries that can go idle, and to eviction ranking by them
+        if header.idle_timeout.is_some() || self.limits.as_ref().is_some_and(Limits::tracks_access)
+        {
+            // Losing a read only makes eviction slightly less accurate,
+            // or lets an idle entry expire a bit early
+            let _ = entry::record_access(&mut file, &header);
+        }
+
+        Ok(Some(RawEntry {
+            codec: header.codec,
+            value,
+            expires_at: header
+                .expires_at
+                .and_then(DateTime::from_timestamp_millis)
+                .map(SystemTime::from),
+            idle_timeout: header.idle_timeout.map(Duration::from_millis),
+            uses_default_expiry: false,
+        }))
+    }
+
+    fn write(&self, key: Vec<u8>, entry: RawEntry) -> Result<(), CacheError> {
+        self.write_tagged(key, entry, &[])
+    }
+
+    fn write_tagged(&self, key: Vec<u8>, entry: RawEntry, tags: &[Tag]) -> Result<(), CacheError> {
+        // Entries encoded by `encode_raw` have the default applied already,
+        // but not those encoded by another cache
+        let entry = entry.or_expiry(self.default_expiry);
+        let expires_at = entry.expires_at.and_then(unix_millis);
+        // The encoded key is stored with the value
+        // so lookups can tell colliding keys apart
+        let header = Header::new(entry.codec, expires_at, entry.idle_timeout, key);
+        let contents = entry::encode(&header, &entry.value)?;
+        if let Some(limits) = &self.limits {
+            limits.check_size(contents.len() as u64)?;
+        }
+
+        {
+            // Nobody else may claim or compact the chain until the entry is in place
+            let _lock = self.locks.entry(self.key_hasher.hash(&header.key))?;
+            let file_path = match self.lookup(&header.key)? {
+                Slot::Occupied(path, ..)
+                | Slot::Taken(path)
+                | Slot::Corrupt(path, _)
+                | Slot::Vacant(path) => path,
+                Slot::Full => return Err(CacheError::KeyCollision),
+            };
+
+            // The value and its expiry are written together and swapped in atomically,
+            // so readers never see a partial entry or a value with someone else's expiry.
+            // Nothing is carried over from the entry being replaced
+            atomic_write::write(&file_path, &contents, self.fsync)?;
+        }
+
+        // Tagging the entry before it is in place would let an `invalidate_tag`
+        // running in between miss it
+        if !tags.is_empty() {
+            let checksum = entry::checksum(&header.key, &entry.value);
+            if let Err(e) = self.tag(&header.key, header.created_at, checksum, tags) {
+                // An entry that can't be found through all of its tags mustn't stay,
+                // unless it was replaced meanwhile by one that can
+                let _ = self.remove_written(&header.key, header.created_at, checksum);
+                return Err(e);
+            }
+        }
+
+        // Eviction locks every entry it removes, so the chain lock has to be released by now
+        self.enforce_limits(&header.key, contents.len() as u64);
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), CacheError> {
+        let _lock = self.locks.entry(self.key_hasher.hash(key))?;
+
+        // Only touch the slot if it actually holds this key.
+        // A corrupt entry can't be read by anyone, so it goes as well
+        if let Slot::Occupied(path, ..) | Slot::Corrupt(path, _) = self.lookup(key)? {
+            self.remove_slot(&path)?;
+        }
+        Ok(())
+    }
+
+    // Removes the entry for `key` only if it is still the one written at
+    // `created_at` with `checksum`
+    fn remove_written(&self, key: &[u8], created_at: i64, checksum: u32) -> Result<(), CacheError> {
+        let _lock = self.locks.entry(self.key_hasher.hash(key))?;
+        match self.lookup(key)? {
+            Slot::Occupied(path, _, header)
+                if header.created_at == created_at && header.checksum == checksum =>
+            {
+                Ok(self.remove_slot(&path)?)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn collect(&self) -> Result<(), CacheError> {
+        // Another process is already collecting, a second pass would find nothing new
+        let Some(_lock) = self.locks.gc()? else {
+            return Ok(());
+        };
+
+        // A file that can't be dealt with shouldn't keep the rest from being collected,
+        // so the pass always finishes and reports the first error afterwards
+        let mut first_error = None;
+        for file in fs::read_dir(&self.cache_dir)? {
+            let res = file
+                .map_err(CacheError::from)
+                .and_then(|file| self.collect_file(&file.path()));
+            if let Err(e) = res {
+                first_error.get_or_insert(e);
+            }
+        }
+        if let Err(e) = self.collect_tags() {
+            first_error.get_or_insert(e);
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+
+    fn clear(&self) -> Result<(), CacheError> {
+        // Keeps eviction from working off a listing that is about to be stale
+        let gc_lock = self.locks.gc_wait()?;
+
+        // Chains are emptied from their ends, so no entry is ever moved
+        // into a slot that was already cleared
+        let mut slots = Vec::new();
+        for file in fs::read_dir(&self.cache_dir)? {
+            let path = file?.path();
+            if let Some((hash, slot)) = parse_slot_name(&path) {
+                slots.push((slot, hash, path));
+            }
+        }
+        slots.sort_unstable_by_key(|(slot, ..)| Reverse(*slot));
+        for (_, hash, path) in slots {
+            let _lock = self.locks.entry(hash)?;
+            self.remove_slot(&path)?;
+        }
+
+        self.clear_tags()?;
+        // Like eviction, only touch the usage count without the garbage
+        // collection lock, so neither ever waits on the other while holding it
+        drop(gc_lock);
+        if let Some(limits) = &self.limits {
+            limits.forget_usage();
+        }
+        Ok(())
+    }
+}
+
+impl<C: Codec + 'static> FsCache<C> {
+    /// Removes every entry. Namespaces are caches of their own and are left
+    /// alone, and entries set while the cache is being cleared may survive.
+    pub async fn clear(&self) -> Result<(), CacheError> {
+        self.run(|inner| inner.clear()).await
+    }
+
+    async fn run<T, F>(&self, f: F) -> Result<T, CacheError>
+    where
+        F: FnOnce(&Inner<C>) -> Result<T, CacheError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let inner = Arc::clone(&self.inner);
+        offload(move || f(&inner)).await
+    }
+}
+
+// Milliseconds since the Unix epoch as entry headers store them. An instant
+// too far out to be stored never comes, one too far back has long passed
+fn unix_millis(time: SystemTime) -> Option<i64> {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(since) => i64::try_from(since.as_millis())
+            .ok()
+            .filter(|millis| DateTime::from_timestamp_millis(*millis).is_some()),
+        Err(e) => Some(i64::try_from(e.duration().as_millis()).map_or(i64::MIN, |millis| -millis)),
+    }
+}
+
+// Runs filesystem work where it can't stall the async executor. Outside a
+// tokio runtime, e.g. on another executor, there is nowhere else to run it
+async fn offload<T, F>(f: F) -> Result<T, CacheError>
+where
+    F: FnOnce() -> Result<T, CacheError> + Send + 'static,
+    T: Send + 'static,
+{
+    #[cfg(feature = "tokio")]
+    if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+        return match runtime.spawn_blocking(f).await {
+            Ok(res) => res,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            // Only happens when the runtime is shutting down
+            Err(e) => Err(CacheError::Backend(e.into())),
+        };
+    }
+    f()
+}
+
+impl<C: Codec + 'static> FsCache<C> {
+    // Like `run`, for work that has a result per item
+    async fn run_batch<T, F>(&self, len: usize, f: F) -> Vec<Result<T, CacheError>>
+    where
+        F: FnOnce(&Inner<C>) -> Vec<Result<T, CacheError>> + Send + 'static,
+        T: Send + 'static,
+    {
+        match self.run(move |inner| Ok(f(inner))).await {
+            Ok(results) => results,
+            // The batch never ran, so every item failed the same way
+            Err(e) => {
+                let reason = e.to_string();
+                (0..len)
+                    .map(|_| Err(CacheError::Backend(reason.clone().into())))
+                    .collect()
+            }
+        }
+    }
+}
+
+impl<C: Codec + 'static> SendCache for FsCache<C> {
+    type Error = CacheError;
+    fn set(
+        &self,
+        key: impl Hash,
+        value: impl Serialize,
+        expiry: impl Into<ExpiryPolicy>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        let key = encode_key(key);
+        let entry = SendCache::encode_raw(self, value, expiry.into());
+        async move {
+            let entry = entry?;
+            self.run(move |inner| inner.write(key, entry)).await
+        }
+    }
+
+    fn get<T>(&self, key: impl Hash) -> impl Future<Output = Result<Option<T>, Self::Error>> + Send
+    where
+        T: DeserializeOwned,
+    {
+        let key = encode_key(key);
+        async move {
+            match self.run(move |inner| inner.read(&key)).await? {
+                Some(entry) => SendCache::decode_raw(self, &entry),
+                None => Ok(None),
+            }
+        }
+    }
+
+    fn invalidate(&self, key: impl Hash) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        let key = encode_key(key);
+        self.run(move |inner| inner.remove(&key))
+    }
+
+    async fn collect_garbage(&self) -> Result<(), Self::Error> {
+        self.run(|inner| inner.collect()).await
+    }
+
+    fn get_many<T, K>(
+        &self,
+        keys: impl IntoIterator<Item = K>,
+    ) -> impl Future<Output = Vec<Result<Option<T>, Self::Error>>> + Send
+    where
+        T: DeserializeOwned + Send,
+        K: Hash,
+    {
+        let keys: Vec<_> = keys.into_iter().map(encode_key).collect();
+        async move {
+            self.run_batch(keys.len(), move |inner| inner.read_many(keys))
+                .await
+                .into_iter()
+                .map(|entry| match entry? {
+                    Some(entry) => SendCache::decode_raw(self, &entry),
+                    None => Ok(None),
+                })
+                .collect()
+        }
+    }
+
+    fn set_many<K, V>(
+        &self,
+        entries: impl IntoIterator<Item = (K, V)>,
+        expiry: impl Into<ExpiryPolicy>,
+    ) -> impl Future<Output = Vec<Result<(), Self::Error>>> + Send
+    where
+        K: Hash,
+        V: Serialize,
+    {
+        let expiry = expiry.into();
+        let entries: Vec<_> = entries
+            .into_iter()
+            .map(|(key, value)| (encode_key(key), SendCache::encode_raw(self, value, expiry)))
+            .collect();
+        self.run_batch(entries.len(), move |inner| inner.write_many(entries))
+    }
+
+    fn invalidate_many<K>(
+        &self,
+        keys: impl IntoIterator<Item = K>,
+    ) -> impl Future<Output = Vec<Result<(), Self::Error>>> + Send
+    where
+        K: Hash,
+    {
+        let keys: Vec<_> = keys.into_iter().map(encode_key).collect();
+        self.run_batch(keys.len(), move |inner| inner.remove_many(keys))
+    }
+
+    fn get_or_insert_with<T, E, F, Fut>(
+        &self,
+        key: impl Hash,
+        expiry: impl Into<ExpiryPolicy>,
+        loader: F,
+    ) -> impl Future<Output = Result<T, E>> + Send
+    where
+        T: Serialize + DeserializeOwned + Send,
+        E: From<Self::Error> + Send,
+        F: FnOnce() -> Fut + Send,
+        Fut: Future<Output = Result<T, E>> + Send,
+    {
+        single_flight::get_or_insert_with(
+            self,
+            &self.flights,
+            EncodedKey::new(key),
+            expiry.into(),
+            loader,
+        )
+    }
+
+    fn get_raw(
+        &self,
+        key: impl Hash,
+    ) -> impl Future<Output = Result<Option<RawEntry>, Self::Error>> + Send {
+        let key = encode_key(key);
+        self.run(move |inner| inner.read(&key))
+    }
+
+    fn set_raw(
+        &self,
+        key: impl Hash,
+        entry: RawEntry,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        let key = encode_key(key);
+        self.run(move |inner| inner.write(key, entry))
+    }
+
+    fn encode_raw(
+        &self,
+        value: impl Serialize,
+        expiry: ExpiryPolicy,
+    ) -> Result<RawEntry, Self::Error> {
+        RawEntry::encode(
+            &self.inner.codec,
+            value,
+            expiry.or(self.inner.default_expiry),
+        )
+    }
+
+    fn decode_raw<T>(&self, entry: &RawEntry) -> Result<Option<T>, Self::Error>
+    where
+        T: DeserializeOwned,
+    {
+        entry.decode(&self.inner.codec)
+    }
+}
+
+impl<C: Codec + 'static> BlockingCache for FsCache<C> {
+    type Error = CacheError;
+    fn set(
+        &self,
+        key: impl Hash,
+        value: impl Serialize,
+        expiry: impl Into<ExpiryPolicy>,
+    ) -> Result<(), Self::Error> {
+        let entry = BlockingCache::encode_raw(self, value, expiry.into())?;
+        self.inner.write(encode_key(key), entry)
+    }
+
+    fn get<T>(&self, key: impl Hash) -> Result<Option<T>, Self::Error>
+    where
+        T: DeserializeOwned,
+    {
+        match self.inner.read(&encode_key(key))? {
+            Some(entry) => BlockingCache::decode_raw(self, &entry),
+            None => Ok(None),
+        }
+    }
+
+    fn invalidate(&self, key: impl Hash) -> Result<(), Self::Error> {
+        self.inner.remove(&encode_key(key))
+    }
+
+    fn collect_garbage(&self) -> Result<(), Self::Error> {
+        self.inner.collect()
+    }
+
+    fn get_raw(&self, key: impl Hash) -> Result<Option<RawEntry>, Self::Error> {
+        self.inner.read(&encode_key(key))
+    }
+
+    fn set_raw(&self, key: impl Hash, entry: RawEntry) -> Result<(), Self::Error> {
+        self.inner.write(encode_key(key), entry)
+    }
+
+    fn encode_raw(
+        &self,
+        value: impl Serialize,
+        expiry: ExpiryPolicy,
+    ) -> Result<RawEntry, Self::Error> {
+        SendCache::encode_raw(self, value, expiry)
+    }
+
+    fn decode_raw<T>(&self, entry: &RawEntry) -> Result<Option<T>, Self::Error>
+    where
+        T: DeserializeOwned,
+    {
+        SendCache::decode_raw(self, entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Instant};
+
+    use super::*;
+    use crate::test_util::{block_on, check_overwrite_semantics, TempDir};
+
+    // Sends every key to the same chain
+    struct ConstHasher;
+
+    impl KeyHasher for ConstHasher {
+        fn id(&self) -> &str {
+            "const-test"
+        }
+
+        fn hash(&self, _key: &[u8]) -> u64 {
+            7
+        }
+    }
+
+    fn colliding_cache(dir: &TempDir, chain_collisions: bool) -> FsCache {
+        FsCache::builder(dir.path().to_path_buf())
+            .key_hasher(ConstHasher)
+            .chain_collisions(chain_collisions)
+            .build()
+            .unwrap()
+    }
+
+    fn slot_names(dir: &TempDir) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|file| file.unwrap().file_name().into_string().unwrap())
+            .filter(|name| parse_slot_name(Path::new(name)).is_some())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn entries_expire_at_their_deadline() {
+        let dir = TempDir::new();
+        let cache = FsCache::new(dir.path().to_path_buf()).unwrap();
+        BlockingCache::set(&cache, "a", 1, Duration::ZERO).unwrap();
+        assert_eq!(BlockingCache::get::<i32>(&cache, "a").unwrap(), None);
+    }
+
+    #[test]
+    fn set_replaces_all_metadata() {
+        let dir = TempDir::new();
+        check_overwrite_semantics(&FsCache::new(dir.path().to_path_buf()).unwrap());
+    }
+
+    #[test]
+    fn accepts_expiries_too_far_out_to_come() {
+        let dir = TempDir::new();
+        let cache = FsCache::new(dir.path().to_path_buf()).unwrap();
+        BlockingCache::set(&cache, "ttl", 1, Duration::MAX).unwrap();
+        let far_out = SystemTime::UNIX_EPOCH + Duration::from_secs(i64::MAX as u64);
+        BlockingCache::set(&cache, "at", 1, far_out).unwrap();
+        let long_ago = SystemTime::UNIX_EPOCH - Duration::from_secs(i64::MAX as u64 / 2);
+        BlockingCache::set(&cache, "past", 1, long_ago).unwrap();
+
+        for key in ["ttl", "at"] {
+            assert_eq!(BlockingCache::get::<i32>(&cache, key).unwrap(), Some(1));
+        }
+        assert_eq!(BlockingCache::get::<i32>(&cache, "past").unwrap(), None);
+    }
+
+    #[test]
+    fn chains_colliding_keys() {
+        let dir = TempDir::new();
+        let cache = colliding_cache(&dir, true);
+        for key in ["a", "b", "c"] {
+            BlockingCache::set(&cache, key, key, None).unwrap();
+        }
+        assert_eq!(slot_names(&dir), ["7", "7-1", "7-2"]);
+        for key in ["a", "b", "c"] {
+            assert_eq!(
+                BlockingCache::get::<String>(&cache, key)
+                    .unwrap()
+                    .as_deref(),
+                Some(key)
+            );
+        }
+    }
+
+    #[test]
+    fn compacts_chains_on_removal() {
+        let dir = TempDir::new();
+        let cache = colliding_cache(&dir, true);
+        for key in ["a", "b", "c"] {
+            BlockingCache::set(&cache, key, key, None).unwrap();
+        }
+
+        // The last entry moves into the hole, so lookups never stop early
+        BlockingCache::invalidate(&cache, "a").unwrap();
+        assert_eq!(slot_names(&dir), ["7", "7-1"]);
+        assert_eq!(BlockingCache::get::<String>(&cache, "a").unwrap(), None);
+        assert_eq!(
+            BlockingCache::get::<String>(&cache, "b")
+                .unwrap()
+                .as_deref(),
+            Some("b")
+        );
+        assert_eq!(
+            BlockingCache::get::<String>(&cache, "c")
+                .unwrap()
+                .as_deref(),
+            Some("c")
+        );
+
+        BlockingCache::invalidate(&cache, "c").unwrap();
+        BlockingCache::invalidate(&cache, "b").unwrap();
+        assert!(slot_names(&dir).is_empty());
+    }
+
+    #[test]
+    fn compacts_chains_on_collection() {
+        let dir = TempDir::new();
+        let cache = colliding_cache(&dir, true);
+        BlockingCache::set(&cache, "a", "a", SystemTime::UNIX_EPOCH).unwrap();
+        BlockingCache::set(&cache, "b", "b", None).unwrap();
+
+        BlockingCache::collect_garbage(&cache).unwrap();
+        assert_eq!(slot_names(&dir), ["7"]);
+        assert_eq!(
+            BlockingCache::get::<String>(&cache, "b")
+                .unwrap()
+                .as_deref(),
+            Some("b")
+        );
+    }
+
+    #[test]
+    fn limits_chain_length() {
+        let dir = TempDir::new();
+        let cache = colliding_cache(&dir, true);
+        for key in 0..MAX_CHAIN_LEN {
+            BlockingCache::set(&cache, key, key, None).unwrap();
+        }
+        assert!(matches!(
+            BlockingCache::set(&cache, MAX_CHAIN_LEN, 0, None),
+            Err(CacheError::KeyCollision)
+        ));
+        // Replacing a key already in the chain still works
+        BlockingCache::set(&cache, 0usize, 1, None).unwrap();
+        assert_eq!(
+            BlockingCache::get::<usize>(&cache, 0usize).unwrap(),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn replaces_colliding_keys_without_chaining() {
+        let dir = TempDir::new();
+        let cache = colliding_cache(&dir, false);
+        BlockingCache::set(&cache, "a", "a", None).unwrap();
+        BlockingCache::set(&cache, "b", "b", None).unwrap();
+
+        assert_eq!(slot_names(&dir), ["7"]);
+        assert_eq!(BlockingCache::get::<String>(&cache, "a").unwrap(), None);
+        // Invalidating a key that lost its slot leaves the winner alone
+        BlockingCache::invalidate(&cache, "a").unwrap();
+        assert_eq!(
+            BlockingCache::get::<String>(&cache, "b")
+                .unwrap()
+                .as_deref(),
+            Some("b")
+        );
+    }
+
+    #[test]
+    fn clears_while_other_threads_write() {
+        let dir = TempDir::new();
+        let cache = FsCache::builder(dir.path().to_path_buf())
+            .max_entries(4)
+            .lock_timeout(Duration::from_secs(5))
+            .build()
+            .unwrap();
+        thread::scope(|scope| {
+            let writer = scope.spawn(|| {
+                for key in 0..200 {
+                    BlockingCache::set(&cache, key, key, None).unwrap();
+                }
+            });
+            while !writer.is_finished() {
+                cache.inner.clear().unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn only_rolls_back_the_entry_it_wrote() {
+        let dir = TempDir::new();
+        let cache = FsCache::new(dir.path().to_path_buf()).unwrap();
+        let key = encode_key("a");
+        BlockingCache::set(&cache, "a", 1, None).unwrap();
+        let Slot::Occupied(_, _, written) = cache.inner.lookup(&key).unwrap() else {
+            panic!("entry not found");
+        };
+
+        // Replaced by the time the rollback runs
+        BlockingCache::set(&cache, "a", 2, None).unwrap();
+        let checksum = entry::checksum(&key, b"1");
+        cache
+            .inner
+            .remove_written(&key, written.created_at, checksum)
+            .unwrap();
+        assert_eq!(BlockingCache::get::<i32>(&cache, "a").unwrap(), Some(2));
+
+        let Slot::Occupied(_, _, current) = cache.inner.lookup(&key).unwrap() else {
+            panic!("entry not found");
+        };
+        cache
+            .inner
+            .remove_written(&key, current.created_at, current.checksum)
+            .unwrap();
+        assert_eq!(BlockingCache::get::<i32>(&cache, "a").unwrap(), None);
+    }
+
+    #[test]
+    fn works_outside_a_tokio_runtime() {
+        let dir = TempDir::new();
+        let cache = FsCache::new(dir.path().to_path_buf()).unwrap();
+        block_on(SendCache::set(&cache, "a", 1, None)).unwrap();
+        assert_eq!(
+            block_on(SendCache::get::<i32>(&cache, "a")).unwrap(),
+            Some(1)
+        );
+        block_on(SendCache::collect_garbage(&cache)).unwrap();
+        block_on(cache.clear()).unwrap();
+
+        let gc = cache.spawn_gc(BackgroundGc::new(Duration::from_millis(1)));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while gc.last_run().is_none() {
+            assert!(Instant::now() < deadline, "no pass finished");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::{fmt::Write, sync::Arc};
+
+use super::{EvictionPolicy, FsCache, FsCacheBuilder, ManifestMismatch};
+use crate::codec::Codec;
+
+// Namespaces are cache directories of their own, kept in this subdirectory
+const NAMESPACE_DIR: &str = "namespaces";
+
+impl<C: Codec + Clone> FsCache<C> {
+    /// Starts opening the namespace `name`, a cache of its own stored inside
+    /// this cache's directory.
+    ///
+    /// Keys in different namespaces never collide, and each namespace can be
+    /// cleared and capped without affecting the others. The builder starts
+    /// out with this cache's settings, including its default expiry, but
+    /// without limits: every namespace sets its own, and namespaces don't
+    /// count towards the limits of this cache.
+    ///
+    /// # Panics
+    ///
+    /// If `name` is empty.
+    pub fn namespace(&self, name: &str) -> FsCacheBuilder<C> {
+        assert!(!name.is_empty(), "namespace names can't be empty");
+        let inner = &self.inner;
+        FsCacheBuilder {
+            cache_dir: inner.cache_dir.join(NAMESPACE_DIR).join(dir_name(name)),
+            codec: inner.codec.clone(),
+            key_hasher: Arc::clone(&inner.key_hasher),
+            on_mismatch: ManifestMismatch::default(),
+            chain_collisions: inner.chain_collisions,
+            fsync: inner.fsync,
+            lock_timeout: inner.locks.timeout(),
+            max_bytes: None,
+            max_entries: None,
+            eviction_policy: EvictionPolicy::default(),
+            default_expiry: inner.default_expiry,
+        }
+    }
+}
+
+// Keeps names readable where possible, escaping every byte that could mean
+// something to the filesystem, so different names never share a directory.
+// Capitals are escaped too, some filesystems don't tell them apart
+fn dir_name(name: &str) -> String {
+    let mut dir_name = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        if byte.is_ascii_lowercase() || byte.is_ascii_digit() || byte == b'_' || byte == b'-' {
+            dir_name.push(char::from(byte));
+        } else {
+            let _ = write!(dir_name, "%{byte:02X}");
+        }
+    }
+    dir_name
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{simple_cache::BlockingCache, test_util::TempDir, Expiry, ExpiryPolicy};
+
+    #[test]
+    fn applies_the_default_expiry_only_to_entries_without_one() {
+        let dir = TempDir::new();
+        let cache = FsCache::builder(dir.path().to_path_buf())
+            .default_expiry(Duration::ZERO)
+            .build()
+            .unwrap();
+        let namespace = cache.namespace("billing").build().unwrap();
+
+        for cache in [&cache, &namespace] {
+            cache.set("none", 1, None).unwrap();
+            cache.set("default", 1, ExpiryPolicy::DEFAULT).unwrap();
+            cache.set("never", 1, ExpiryPolicy::NEVER).unwrap();
+            cache.set("explicit never", 1, Expiry::Never).unwrap();
+
+            assert_eq!(cache.get::<i32>("none").unwrap(), None);
+            assert_eq!(cache.get::<i32>("default").unwrap(), None);
+            assert_eq!(cache.get::<i32>("never").unwrap(), Some(1));
+            assert_eq!(cache.get::<i32>("explicit never").unwrap(), Some(1));
+        }
+    }
+
+    #[test]
+    fn keeps_namespaces_apart() {
+        let dir = TempDir::new();
+        let cache = FsCache::new(dir.path().to_path_buf()).unwrap();
+        let billing = cache.namespace("billing").build().unwrap();
+        let other = cache.namespace("Billing").build().unwrap();
+
+        cache.set("key", 0, None).unwrap();
+        billing.set("key", 1, None).unwrap();
+        other.set("key", 2, None).unwrap();
+        BlockingCache::collect_garbage(&cache).unwrap();
+        assert_eq!(cache.get::<i32>("key").unwrap(), Some(0));
+        assert_eq!(billing.get::<i32>("key").unwrap(), Some(1));
+        assert_eq!(other.get::<i32>("key").unwrap(), Some(2));
+
+        billing.inner.clear().unwrap();
+        assert_eq!(billing.get::<i32>("key").unwrap(), None);
+        assert_eq!(cache.get::<i32>("key").unwrap(), Some(0));
+        assert_eq!(other.get::<i32>("key").unwrap(), Some(2));
+    }
+}
@@ -0,0 +1,467 @@
+use serde::Serialize;
+use siphasher::sip128::SipHasher13;
+use std::{
+    fs,
+    future::Future,
+    hash::Hash,
+    io,
+    path::{Path, PathBuf},
+};
+
+use super::{atomic_write, remove_if_exists, FsCache, Inner, Slot};
+use crate::{
+    codec::Codec, error::CacheError, expiry::ExpiryPolicy, key_hasher::encode_key,
+    key_path::KeyPath, simple_cache::SendCache,
+};
+
+// Every tag has a directory in here, holding a marker file for each entry set
+// with the tag. Markers are named `<hash>-<created_at>-<checksum>` after the
+// entry and contain its encoded key, so a marker only ever matches the entry
+// it was written for, not a later one stored under the same key
+const TAG_DIR: &str = "tags";
+
+// The same for the prefixes of paths set with `set_path`, each prefix is a tag
+// of the entry. They are kept apart so no tag can pass for a prefix
+const PREFIX_DIR: &str = "prefixes";
+
+// A tag of an entry, either one it was set with or a prefix of its path
+pub(super) struct Tag {
+    index: &'static str,
+    tag: Vec<u8>,
+}
+
+impl<C: Codec + 'static> FsCache<C> {
+    /// Like `set`, also tagging the entry with each of `tags` so
+    /// [`invalidate_tag`](Self::invalidate_tag) can find it.
+    ///
+    /// Tags belong to the entry: setting the key again, with or without
+    /// tags, leaves the new entry with only the tags it was set with.
+    pub fn set_with_tags<T: Hash>(
+        &self,
+        key: impl Hash,
+        value: impl Serialize,
+        expiry: impl Into<ExpiryPolicy>,
+        tags: impl IntoIterator<Item = T>,
+    ) -> impl Future<Output = Result<(), CacheError>> + Send + '_ {
+        let key = encode_key(key);
+        let entry = SendCache::encode_raw(self, value, expiry.into());
+        let tags: Vec<_> = tags
+            .into_iter()
+            .map(|tag| Tag {
+                index: TAG_DIR,
+                tag: encode_key(tag),
+            })
+            .collect();
+        async move {
+            let entry = entry?;
+            self.run(move |inner| inner.write_tagged(key, entry, &tags))
+                .await
+        }
+    }
+
+    /// Removes every entry tagged with `tag`. Entries tagged while this runs
+    /// may survive.
+    ///
+    /// The tag index is kept on disk next to the entries, so it works across
+    /// restarts and processes. `collect_garbage` drops what it knows about
+    /// entries that have expired or were removed since.
+    pub fn invalidate_tag(
+        &self,
+        tag: impl Hash,
+    ) -> impl Future<Output = Result<(), CacheError>> + Send + '_ {
+        let tag = Tag {
+            index: TAG_DIR,
+            tag: encode_key(tag),
+        };
+        self.run(move |inner| inner.invalidate_tag(&tag))
+    }
+
+    /// Like `set`, also indexing every prefix of `path` so
+    /// [`invalidate_prefix`](Self::invalidate_prefix) can find the entry. That
+    /// costs a small file per segment of the path.
+    ///
+    /// The entry can be read with `get(path)` like any other. Paths stored
+    /// with `set` instead aren't indexed, and are never found by prefix.
+    pub fn set_path(
+        &self,
+        path: impl Into<KeyPath>,
+        value: impl Serialize,
+        expiry: impl Into<ExpiryPolicy>,
+    ) -> impl Future<Output = Result<(), CacheError>> + Send + '_ {
+        let path = path.into();
+        let tags: Vec<_> = path
+            .prefixes()
+            .map(|prefix| Tag {
+                index: PREFIX_DIR,
+                tag: encode_key(prefix),
+            })
+            .collect();
+        let key = encode_key(path);
+        let entry = SendCache::encode_raw(self, value, expiry.into());
+        async move {
+            let entry = entry?;
+            self.run(move |inner| inner.write_tagged(key, entry, &tags))
+                .await
+        }
+    }
+
+    /// Removes every entry set with `set_path` under a path starting with
+    /// `prefix`, including `prefix` itself. Like `invalidate_tag`, this only
+    /// reads the index, not the whole cache. An empty prefix matches nothing,
+    /// see `clear` for that.
+    pub fn invalidate_prefix(
+        &self,
+        prefix: impl Into<KeyPath>,
+    ) -> impl Future<Output = Result<(), CacheError>> + Send + '_ {
+        let prefix = prefix.into();
+        let tag = (!prefix.is_empty()).then(|| Tag {
+            index: PREFIX_DIR,
+            tag: encode_key(prefix),
+        });
+        self.run(move |inner| match tag {
+            Some(tag) => inner.invalidate_tag(&tag),
+            None => Ok(()),
+        })
+    }
+}
+
+impl<C> Inner<C> {
+    // Tags can be any length, their hash makes for a usable directory name
+    fn tag_dir(&self, tag: &Tag) -> PathBuf {
+        let hash = SipHasher13::new().hash(&tag.tag).as_u128();
+        self.cache_dir.join(tag.index).join(format!("{hash:032x}"))
+    }
+
+    pub(super) fn tag(
+        &self,
+        key: &[u8],
+        created_at: i64,
+        checksum: u32,
+        tags: &[Tag],
+    ) -> Result<(), CacheError> {
+        let name = format!("{}-{created_at}-{checksum:08x}", self.key_hasher.hash(key));
+        for tag in tags {
+            let dir = self.tag_dir(tag);
+            loop {
+                fs::create_dir_all(&dir)?;
+                match atomic_write::write(&dir.join(&name), key, self.fsync) {
+                    // Garbage collection removed the directory since it was empty
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    res => res?,
+                }
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn invalidate_tag(&self, tag: &Tag) -> Result<(), CacheError> {
+        let markers = match fs::read_dir(self.tag_dir(tag)) {
+            Ok(markers) => markers,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        for marker in markers {
+            let path = marker?.path();
+            let Some(key) = self.read_marker(&path)? else {
+                continue;
+            };
+            {
+                let _lock = self.locks.entry(self.key_hasher.hash(&key))?;
+                if let Some(entry_path) = self.tagged_entry(&path, &key)? {
+                    self.remove_slot(&entry_path)?;
+                }
+            }
+            remove_if_exists(&path)?;
+        }
+        Ok(())
+    }
+
+    // Drops markers whose entries are gone, and then tags without entries
+    pub(super) fn collect_tags(&self) -> Result<(), CacheError> {
+        let mut first_error = None;
+        for index in [TAG_DIR, PREFIX_DIR] {
+            if let Err(e) = self.collect_index(index) {
+                first_error.get_or_insert(e);
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+
+    fn collect_index(&self, index: &str) -> Result<(), CacheError> {
+        let tags = match fs::read_dir(self.cache_dir.join(index)) {
+            Ok(tags) => tags,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut first_error = None;
+        for tag in tags {
+            let res = tag.map_err(CacheError::from).and_then(|tag| {
+                let dir = tag.path();
+                for marker in fs::read_dir(&dir)? {
+                    self.collect_marker(&marker?.path())?;
+                }
+                // Fails if the tag was set again in the meantime, which is fine
+                let _ = fs::remove_dir(&dir);
+                Ok(())
+            });
+            if let Err(e) = res {
+                first_error.get_or_insert(e);
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+
+    // Returns the size of the marker if it was removed
+    pub(super) fn collect_marker(&self, path: &Path) -> Result<Option<u64>, CacheError> {
+        let is_live = match self.read_marker(path)? {
+            Some(key) => self.tagged_entry(path, &key)?.is_some(),
+            None => !atomic_write::is_abandoned(path)?,
+        };
+        if is_live {
+            return Ok(None);
+        }
+        let size = fs::metadata(path).map_or(0, |metadata| metadata.len());
+        remove_if_exists(path)?;
+        Ok(Some(size))
+    }
+
+    // The directories of every tag and prefix, for the background collector
+    // to go through one at a time
+    pub(super) fn tag_dirs(&self) -> Vec<Result<PathBuf, io::Error>> {
+        let mut dirs = Vec::new();
+        for index in [TAG_DIR, PREFIX_DIR] {
+            match fs::read_dir(self.cache_dir.join(index)) {
+                Ok(tags) => dirs.extend(tags.map(|tag| tag.map(|tag| tag.path()))),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => dirs.push(Err(e)),
+            }
+        }
+        dirs
+    }
+
+    pub(super) fn clear_tags(&self) -> Result<(), io::Error> {
+        for index in [TAG_DIR, PREFIX_DIR] {
+            match fs::remove_dir_all(self.cache_dir.join(index)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the key the marker at `path` was written for, or `None` if
+    // it isn't a marker (anymore)
+    fn read_marker(&self, path: &Path) -> Result<Option<Vec<u8>>, io::Error> {
+        if parse_marker_name(path).is_none() {
+            return Ok(None);
+        }
+        match fs::read(path) {
+            Ok(key) => Ok(Some(key)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Finds the unexpired entry the marker at `path` was written for
+    fn tagged_entry(&self, marker: &Path, key: &[u8]) -> Result<Option<PathBuf>, CacheError> {
+        let Some((created_at, checksum)) = parse_marker_name(marker) else {
+            return Ok(None);
+        };
+        match self.lookup(key)? {
+            Slot::Occupied(path, _, header)
+                if header.created_at == created_at
+                    && header.checksum == checksum
+                    && !header.is_expired() =>
+            {
+                Ok(Some(path))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+fn parse_marker_name(path: &Path) -> Option<(i64, u32)> {
+    let name = path.file_name()?.to_str()?;
+    let mut parts = name.split('-');
+    let (hash, created_at, checksum) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() || hash.parse::<u64>().is_err() {
+        return None;
+    }
+    Some((
+        created_at.parse().ok()?,
+        u32::from_str_radix(checksum, 16).ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn open(dir: &TempDir) -> FsCache {
+        FsCache::new(dir.path().to_path_buf()).unwrap()
+    }
+
+    // Marker files of every tag in `index`
+    fn markers(dir: &TempDir, index: &str) -> Vec<PathBuf> {
+        let Ok(tags) = fs::read_dir(dir.join(index)) else {
+            return Vec::new();
+        };
+        tags.flat_map(|tag| fs::read_dir(tag.unwrap().path()).unwrap())
+            .map(|marker| marker.unwrap().path())
+            .collect()
+    }
+
+    async fn get(cache: &FsCache, key: &str) -> Option<i32> {
+        SendCache::get(cache, key).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn invalidates_tagged_entries() {
+        let dir = TempDir::new();
+        let cache = open(&dir);
+        cache.set_with_tags("a", 1, None, ["x", "y"]).await.unwrap();
+        cache.set_with_tags("b", 2, None, ["y"]).await.unwrap();
+        cache.set_with_tags("c", 3, None, ["z"]).await.unwrap();
+
+        cache.invalidate_tag("y").await.unwrap();
+        assert_eq!(get(&cache, "a").await, None);
+        assert_eq!(get(&cache, "b").await, None);
+        assert_eq!(get(&cache, "c").await, Some(3));
+    }
+
+    #[tokio::test]
+    async fn keeps_the_tag_index_across_reopening() {
+        let dir = TempDir::new();
+        open(&dir).set_with_tags("a", 1, None, ["x"]).await.unwrap();
+
+        let cache = open(&dir);
+        assert_eq!(get(&cache, "a").await, Some(1));
+        cache.invalidate_tag("x").await.unwrap();
+        assert_eq!(get(&cache, "a").await, None);
+        assert!(markers(&dir, TAG_DIR).is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_invalidates_the_entry_that_was_tagged() {
+        let dir = TempDir::new();
+        let cache = open(&dir);
+        cache.set_with_tags("a", 1, None, ["x"]).await.unwrap();
+        // Later entries under the same key don't inherit the tag
+        SendCache::set(&cache, "a", 2, None).await.unwrap();
+
+        cache.invalidate_tag("x").await.unwrap();
+        assert_eq!(get(&cache, "a").await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn collects_markers_of_entries_that_are_gone() {
+        let dir = TempDir::new();
+        let cache = open(&dir);
+        cache.set_with_tags("live", 1, None, ["x"]).await.unwrap();
+        cache
+            .set_with_tags("expired", 1, Duration::ZERO, ["x", "y"])
+            .await
+            .unwrap();
+        cache
+            .set_with_tags("replaced", 1, None, ["z"])
+            .await
+            .unwrap();
+        SendCache::set(&cache, "replaced", 2, None).await.unwrap();
+        cache
+            .set_with_tags("removed", 1, None, ["z"])
+            .await
+            .unwrap();
+        SendCache::invalidate(&cache, "removed").await.unwrap();
+        assert_eq!(markers(&dir, TAG_DIR).len(), 5);
+
+        SendCache::collect_garbage(&cache).await.unwrap();
+        assert_eq!(markers(&dir, TAG_DIR).len(), 1);
+        // Tags without entries go as well
+        assert_eq!(fs::read_dir(dir.join(TAG_DIR)).unwrap().count(), 1);
+        assert_eq!(get(&cache, "live").await, Some(1));
+        assert_eq!(get(&cache, "replaced").await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn drops_entries_that_couldnt_be_tagged() {
+        let dir = TempDir::new();
+        let cache = open(&dir);
+        // Keeps tag directories from being created
+        fs::write(dir.join(TAG_DIR), "").unwrap();
+
+        assert!(cache.set_with_tags("a", 1, None, ["x"]).await.is_err());
+        assert_eq!(get(&cache, "a").await, None);
+    }
+
+    async fn get_path(cache: &FsCache, path: impl Into<KeyPath>) -> Option<i32> {
+        SendCache::get(cache, path.into()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn invalidates_paths_by_prefix() {
+        let dir = TempDir::new();
+        let cache = open(&dir);
+        cache.set_path(["user", "42"], 1, None).await.unwrap();
+        cache
+            .set_path(["user", "42", "profile"], 2, None)
+            .await
+            .unwrap();
+        cache.set_path(["user", "420"], 3, None).await.unwrap();
+        cache.set_path(["user/42"], 4, None).await.unwrap();
+        cache.set_path(["org", "42"], 5, None).await.unwrap();
+        // Not indexed, so never found by prefix
+        SendCache::set(&cache, KeyPath::from(["user", "42", "plain"]), 6, None)
+            .await
+            .unwrap();
+
+        cache.invalidate_prefix(["user", "42"]).await.unwrap();
+        assert_eq!(get_path(&cache, ["user", "42"]).await, None);
+        assert_eq!(get_path(&cache, ["user", "42", "profile"]).await, None);
+        assert_eq!(get_path(&cache, ["user", "420"]).await, Some(3));
+        assert_eq!(get_path(&cache, ["user/42"]).await, Some(4));
+        assert_eq!(get_path(&cache, ["org", "42"]).await, Some(5));
+        assert_eq!(get_path(&cache, ["user", "42", "plain"]).await, Some(6));
+
+        cache.invalidate_prefix(["user"]).await.unwrap();
+        assert_eq!(get_path(&cache, ["user", "420"]).await, None);
+        assert_eq!(get_path(&cache, ["org", "42"]).await, Some(5));
+    }
+
+    #[tokio::test]
+    async fn invalidates_nothing_for_the_empty_prefix() {
+        let dir = TempDir::new();
+        let cache = open(&dir);
+        cache.set_path(["user", "42"], 1, None).await.unwrap();
+        cache.invalidate_prefix(KeyPath::new()).await.unwrap();
+        assert_eq!(get_path(&cache, ["user", "42"]).await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn collects_prefixes_of_entries_that_are_gone() {
+        let dir = TempDir::new();
+        let cache = open(&dir);
+        cache.set_path(["user", "42"], 1, None).await.unwrap();
+        cache
+            .set_path(["user", "7"], 2, Duration::ZERO)
+            .await
+            .unwrap();
+        cache.set_path(["org", "1"], 3, None).await.unwrap();
+        SendCache::invalidate(&cache, KeyPath::from(["org", "1"]))
+            .await
+            .unwrap();
+        assert_eq!(markers(&dir, PREFIX_DIR).len(), 6);
+
+        SendCache::collect_garbage(&cache).await.unwrap();
+        // What is left of `user/42`: its own prefix and `user`
+        assert_eq!(markers(&dir, PREFIX_DIR).len(), 2);
+        assert_eq!(fs::read_dir(dir.join(PREFIX_DIR)).unwrap().count(), 2);
+        cache.invalidate_prefix(["user"]).await.unwrap();
+        assert_eq!(get_path(&cache, ["user", "42"]).await, None);
+    }
+}
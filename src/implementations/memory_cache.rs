@@ -0,0 +1,648 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    hash::Hash,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+    vec,
+};
+
+use futures_core::Stream;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    codec::{Codec, Json},
+    error::CacheError,
+    expiry::ExpiryPolicy,
+    key_hasher::{encode_key, EncodedKey},
+    simple_cache::{BlockingCache, EntryInfo, RawEntry, SendCache},
+    single_flight::{self, SingleFlight},
+};
+
+/// An in-process cache with the same semantics as `FsCache`.
+///
+/// Values are kept serialized, so `get` can return any type the value
+/// deserializes into, exactly like the on-disk cache. When a limit is set,
+/// expired entries are dropped first and then the oldest ones.
+///
+/// Reads only take a shared lock, including those that keep an entry with an
+/// idle timeout alive, so they never wait on each other.
+pub struct MemoryCache<C = Json> {
+    codec: C,
+    max_entries: Option<usize>,
+    max_bytes: Option<usize>,
+    state: RwLock<State>,
+    flights: SingleFlight,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<Vec<u8>, Entry>,
+    // Insertion order, used to pick eviction victims
+    order: BTreeMap<u64, Vec<u8>>,
+    next_seq: u64,
+    bytes: usize,
+}
+
+struct Entry {
+    codec: u8,
+    value: Vec<u8>,
+    expires_at: Option<Instant>,
+    idle_timeout: Option<Duration>,
+    stored_at: Instant,
+    // Nanoseconds from `stored_at` to the latest read. Atomic so reads can
+    // record themselves under the shared lock, without serializing on the
+    // exclusive one
+    read_after: AtomicU64,
+    created_at: SystemTime,
+    seq: u64,
+}
+
+impl Entry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.deadline().is_some_and(|deadline| deadline <= now)
+    }
+
+    fn accessed_at(&self) -> Instant {
+        self.stored_at + Duration::from_nanos(self.read_after.load(Ordering::Relaxed))
+    }
+
+    fn record_access(&self, now: Instant) {
+        let read_after = now.saturating_duration_since(self.stored_at).as_nanos();
+        self.read_after.fetch_max(
+            u64::try_from(read_after).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+
+    // The earlier of the expiry and the idle timeout running out.
+    // An idle timeout too long to be represented never runs out
+    fn deadline(&self) -> Option<Instant> {
+        let idle_deadline = self
+            .idle_timeout
+            .and_then(|idle_timeout| self.accessed_at().checked_add(idle_timeout));
+        match (self.expires_at, idle_deadline) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+impl State {
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&entry.seq);
+        self.bytes -= key.len() + entry.value.len();
+        Some(entry)
+    }
+
+    fn remove_expired(&mut self, now: Instant) {
+        let expired: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.remove(&key);
+        }
+    }
+
+    fn remove_oldest(&mut self) {
+        if let Some((_, key)) = self.order.pop_first() {
+            if let Some(entry) = self.entries.remove(&key) {
+                self.bytes -= key.len() + entry.value.len();
+            }
+        }
+    }
+}
+
+impl MemoryCache {
+    pub fn new() -> Self {
+        Self::builder().build()
+    }
+
+    pub fn builder() -> MemoryCacheBuilder {
+        MemoryCacheBuilder {
+            codec: Json,
+            max_entries: None,
+            max_bytes: None,
+        }
+    }
+}
+
+impl Default for MemoryCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C> MemoryCache<C> {
+    fn read_state(&self) -> RwLockReadGuard<'_, State> {
+        self.state.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write_state(&self) -> RwLockWriteGuard<'_, State> {
+        self.state.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Number of entries currently held, including expired ones that have
+    /// not been collected yet.
+    pub fn len(&self) -> usize {
+        self.read_state().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes currently held by keys and serialized values.
+    pub fn size_in_bytes(&self) -> usize {
+        self.read_state().bytes
+    }
+
+    /// Lists the entries that haven't expired, in no particular order. The
+    /// listing is a snapshot taken when this is called.
+    pub fn entries(&self) -> impl Stream<Item = Result<EntryInfo, CacheError>> + Send + Unpin {
+        let now = Instant::now();
+        let system_now = SystemTime::now();
+        let entries: Vec<_> = self
+            .read_state()
+            .entries
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, entry)| {
+                Ok(EntryInfo {
+                    key: EncodedKey::from_bytes(key.clone()),
+                    size: (key.len() + entry.value.len()) as u64,
+                    created_at: entry.created_at,
+                    expires_at: entry
+                        .deadline()
+                        .and_then(|deadline| system_now.checked_add(deadline - now)),
+                })
+            })
+            .collect();
+        Snapshot(entries.into_iter())
+    }
+
+    /// Lists the keys of the entries that haven't expired, like
+    /// [`entries`](Self::entries).
+    pub fn keys(&self) -> impl Stream<Item = Result<EncodedKey, CacheError>> + Send + Unpin {
+        let now = Instant::now();
+        let keys: Vec<_> = self
+            .read_state()
+            .entries
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, _)| Ok(EncodedKey::from_bytes(key.clone())))
+            .collect();
+        Snapshot(keys.into_iter())
+    }
+}
+
+// Hands out a listing that was taken up front
+struct Snapshot<T>(vec::IntoIter<T>);
+
+impl<T: Unpin> Stream for Snapshot<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<T>> {
+        Poll::Ready(self.0.next())
+    }
+}
+
+pub struct MemoryCacheBuilder<C = Json> {
+    codec: C,
+    max_entries: Option<usize>,
+    max_bytes: Option<usize>,
+}
+
+impl<C: Codec> MemoryCacheBuilder<C> {
+    /// Sets the format values are stored in. Defaults to [`Json`].
+    pub fn codec<D: Codec>(self, codec: D) -> MemoryCacheBuilder<D> {
+        MemoryCacheBuilder {
+            codec,
+            max_entries: self.max_entries,
+            max_bytes: self.max_bytes,
+        }
+    }
+
+    /// Caps the number of entries. Unbounded by default. With a cap of 0
+    /// every `set` fails with `CacheError::CapacityExceeded`.
+    pub fn max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = Some(max_entries);
+        self
+    }
+
+    /// Caps the bytes used by keys and serialized values. Unbounded by default.
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    pub fn build(self) -> MemoryCache<C> {
+        MemoryCache {
+            codec: self.codec,
+            max_entries: self.max_entries,
+            max_bytes: self.max_bytes,
+            state: RwLock::default(),
+            flights: SingleFlight::default(),
+        }
+    }
+}
+
+impl<C: Codec> MemoryCache<C> {
+    fn read(&self, key: &[u8]) -> Option<RawEntry> {
+        let now = Instant::now();
+        let state = self.read_state();
+        let entry = match state.entries.get(key) {
+            Some(entry) if !entry.is_expired(now) => entry,
+            _ => return None,
+        };
+
+        let raw = RawEntry {
+            codec: entry.codec,
+            value: entry.value.clone(),
+            expires_at: entry
+                .expires_at
+                .and_then(|expires_at| SystemTime::now().checked_add(expires_at - now)),
+            idle_timeout: entry.idle_timeout,
+            uses_default_expiry: false,
+        };
+
+        // Only entries that can go idle need to record the read
+        if entry.idle_timeout.is_some() {
+            entry.record_access(now);
+        }
+        Some(raw)
+    }
+
+    fn write(&self, key: Vec<u8>, entry: RawEntry) -> Result<(), CacheError> {
+        let size = key.len() + entry.value.len();
+
+        if let Some(max_bytes) = self.max_bytes.filter(|max_bytes| size > *max_bytes) {
+            return Err(CacheError::CapacityExceeded {
+                size: size as u64,
+                capacity: max_bytes as u64,
+            });
+        }
+        // A cache that may not hold any entry has no room for this one either
+        if self.max_entries == Some(0) {
+            return Err(CacheError::CapacityExceeded {
+                size: size as u64,
+                capacity: 0,
+            });
+        }
+
+        let now = Instant::now();
+        // An expiry that already passed leaves the entry expired right away,
+        // one too far out to be represented never comes
+        let expires_at = entry.expires_at.and_then(|expires_at| {
+            now.checked_add(
+                expires_at
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default(),
+            )
+        });
+
+        let mut state = self.write_state();
+        state.remove(&key);
+
+        // Make room, dropping expired entries before live ones
+        let over_budget = |state: &State| {
+            self.max_entries
+                .is_some_and(|max_entries| state.entries.len() >= max_entries)
+                || self
+                    .max_bytes
+                    .is_some_and(|max_bytes| state.bytes + size > max_bytes)
+        };
+        if over_budget(&state) {
+            state.remove_expired(now);
+        }
+        while over_budget(&state) && !state.entries.is_empty() {
+            state.remove_oldest();
+        }
+
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.order.insert(seq, key.clone());
+        state.bytes += size;
+        state.entries.insert(
+            key,
+            Entry {
+                codec: entry.codec,
+                value: entry.value,
+                expires_at,
+                idle_timeout: entry.idle_timeout,
+                stored_at: now,
+                read_after: AtomicU64::new(0),
+                created_at: SystemTime::now(),
+                seq,
+            },
+        );
+
+        Ok(())
+    }
+}
+
+impl<C: Codec> SendCache for MemoryCache<C> {
+    type Error = CacheError;
+    fn set(
+        &self,
+        key: impl Hash,
+        value: impl Serialize,
+        expiry: impl Into<ExpiryPolicy>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        let key = encode_key(key);
+        let entry = SendCache::encode_raw(self, value, expiry.into());
+        async move { self.write(key, entry?) }
+    }
+
+    fn get<T>(&self, key: impl Hash) -> impl Future<Output = Result<Option<T>, Self::Error>> + Send
+    where
+        T: DeserializeOwned,
+    {
+        let key = encode_key(key);
+        async move {
+            match self.read(&key) {
+                Some(entry) => SendCache::decode_raw(self, &entry),
+                None => Ok(None),
+            }
+        }
+    }
+
+    fn invalidate(&self, key: impl Hash) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        let key = encode_key(key);
+        async move {
+            self.write_state().remove(&key);
+            Ok(())
+        }
+    }
+
+    async fn collect_garbage(&self) -> Result<(), Self::Error> {
+        self.write_state().remove_expired(Instant::now());
+        Ok(())
+    }
+
+    fn get_or_insert_with<T, E, F, Fut>(
+        &self,
+        key: impl Hash,
+        expiry: impl Into<ExpiryPolicy>,
+        loader: F,
+    ) -> impl Future<Output = Result<T, E>> + Send
+    where
+        T: Serialize + DeserializeOwned + Send,
+        E: From<Self::Error> + Send,
+        F: FnOnce() -> Fut + Send,
+        Fut: Future<Output = Result<T, E>> + Send,
+    {
+        single_flight::get_or_insert_with(
+            self,
+            &self.flights,
+            EncodedKey::new(key),
+            expiry.into(),
+            loader,
+        )
+    }
+
+    fn get_raw(
+        &self,
+        key: impl Hash,
+    ) -> impl Future<Output = Result<Option<RawEntry>, Self::Error>> + Send {
+        let key = encode_key(key);
+        async move { Ok(self.read(&key)) }
+    }
+
+    fn set_raw(
+        &self,
+        key: impl Hash,
+        entry: RawEntry,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        let key = encode_key(key);
+        async move { self.write(key, entry) }
+    }
+
+    fn encode_raw(
+        &self,
+        value: impl Serialize,
+        expiry: ExpiryPolicy,
+    ) -> Result<RawEntry, Self::Error> {
+        // There is no default expiry to leave to, entries without one never expire
+        RawEntry::encode(&self.codec, value, expiry.or(ExpiryPolicy::NEVER))
+    }
+
+    fn decode_raw<T>(&self, entry: &RawEntry) -> Result<Option<T>, Self::Error>
+    where
+        T: DeserializeOwned,
+    {
+        entry.decode(&self.codec)
+    }
+}
+
+impl<C: Codec> BlockingCache for MemoryCache<C> {
+    type Error = CacheError;
+    fn set(
+        &self,
+        key: impl Hash,
+        value: impl Serialize,
+        expiry: impl Into<ExpiryPolicy>,
+    ) -> Result<(), Self::Error> {
+        let entry = BlockingCache::encode_raw(self, value, expiry.into())?;
+        self.write(encode_key(key), entry)
+    }
+
+    fn get<T>(&self, key: impl Hash) -> Result<Option<T>, Self::Error>
+    where
+        T: DeserializeOwned,
+    {
+        match self.read(&encode_key(key)) {
+            Some(entry) => BlockingCache::decode_raw(self, &entry),
+            None => Ok(None),
+        }
+    }
+
+    fn invalidate(&self, key: impl Hash) -> Result<(), Self::Error> {
+        self.write_state().remove(&encode_key(key));
+        Ok(())
+    }
+
+    fn collect_garbage(&self) -> Result<(), Self::Error> {
+        self.write_state().remove_expired(Instant::now());
+        Ok(())
+    }
+
+    fn get_raw(&self, key: impl Hash) -> Result<Option<RawEntry>, Self::Error> {
+        Ok(self.read(&encode_key(key)))
+    }
+
+    fn set_raw(&self, key: impl Hash, entry: RawEntry) -> Result<(), Self::Error> {
+        self.write(encode_key(key), entry)
+    }
+
+    fn encode_raw(
+        &self,
+        value: impl Serialize,
+        expiry: ExpiryPolicy,
+    ) -> Result<RawEntry, Self::Error> {
+        SendCache::encode_raw(self, value, expiry)
+    }
+
+    fn decode_raw<T>(&self, entry: &RawEntry) -> Result<Option<T>, Self::Error>
+    where
+        T: DeserializeOwned,
+    {
+        SendCache::decode_raw(self, entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{block_on, check_overwrite_semantics, collect};
+
+    #[test]
+    fn refuses_entries_with_max_entries_zero() {
+        let cache = MemoryCache::builder().max_entries(0).build();
+        assert!(matches!(
+            BlockingCache::set(&cache, "a", 1, None),
+            Err(CacheError::CapacityExceeded { capacity: 0, .. })
+        ));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn refuses_entries_larger_than_max_bytes() {
+        let cache = MemoryCache::builder().max_bytes(8).build();
+        assert!(matches!(
+            BlockingCache::set(&cache, "a", "too long to fit", None),
+            Err(CacheError::CapacityExceeded { capacity: 8, .. })
+        ));
+    }
+
+    #[test]
+    fn evicts_oldest_entries_first() {
+        let cache = MemoryCache::builder().max_entries(2).build();
+        for key in 0..3 {
+            BlockingCache::set(&cache, key, key, None).unwrap();
+        }
+        assert_eq!(BlockingCache::get::<i32>(&cache, 0).unwrap(), None);
+        assert_eq!(BlockingCache::get::<i32>(&cache, 1).unwrap(), Some(1));
+        assert_eq!(BlockingCache::get::<i32>(&cache, 2).unwrap(), Some(2));
+    }
+
+    #[test]
+    fn evicts_expired_entries_before_live_ones() {
+        let cache = MemoryCache::builder().max_entries(2).build();
+        BlockingCache::set(&cache, 0, 0, None).unwrap();
+        BlockingCache::set(&cache, 1, 1, Duration::ZERO).unwrap();
+        BlockingCache::set(&cache, 2, 2, None).unwrap();
+        assert_eq!(BlockingCache::get::<i32>(&cache, 0).unwrap(), Some(0));
+        assert_eq!(BlockingCache::get::<i32>(&cache, 2).unwrap(), Some(2));
+    }
+
+    #[test]
+    fn entries_expire_at_their_deadline() {
+        let cache = MemoryCache::new();
+        BlockingCache::set(&cache, "a", 1, Duration::ZERO).unwrap();
+        assert_eq!(BlockingCache::get::<i32>(&cache, "a").unwrap(), None);
+    }
+
+    #[test]
+    fn set_replaces_all_metadata() {
+        check_overwrite_semantics(&MemoryCache::new());
+    }
+
+    #[test]
+    fn accepts_idle_timeouts_too_long_to_run_out() {
+        let cache = MemoryCache::new();
+        BlockingCache::set(&cache, "a", 1, ExpiryPolicy::tti(Duration::MAX)).unwrap();
+        assert_eq!(BlockingCache::get::<i32>(&cache, "a").unwrap(), Some(1));
+        assert_eq!(BlockingCache::get::<i32>(&cache, "a").unwrap(), Some(1));
+        BlockingCache::collect_garbage(&cache).unwrap();
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn accepts_expiries_too_far_out_to_come() {
+        let cache = MemoryCache::new();
+        BlockingCache::set(&cache, "ttl", 1, Duration::MAX).unwrap();
+        let far_out = SystemTime::UNIX_EPOCH + Duration::from_secs(i64::MAX as u64);
+        BlockingCache::set(&cache, "at", 1, far_out).unwrap();
+        for key in ["ttl", "at"] {
+            assert_eq!(BlockingCache::get::<i32>(&cache, key).unwrap(), Some(1));
+        }
+    }
+
+    #[test]
+    fn reads_keep_idle_entries_alive() {
+        let cache = MemoryCache::new();
+        let tti = Duration::from_millis(200);
+        BlockingCache::set(&cache, "a", 1, ExpiryPolicy::tti(tti)).unwrap();
+        for _ in 0..6 {
+            std::thread::sleep(tti / 4);
+            assert_eq!(BlockingCache::get::<i32>(&cache, "a").unwrap(), Some(1));
+        }
+        std::thread::sleep(tti * 2);
+        assert_eq!(BlockingCache::get::<i32>(&cache, "a").unwrap(), None);
+    }
+
+    #[test]
+    fn reads_of_idle_entries_run_concurrently() {
+        let cache = MemoryCache::new();
+        BlockingCache::set(&cache, "a", 1, ExpiryPolicy::tti(Duration::from_secs(60))).unwrap();
+        // Would deadlock if reads recording their access took the write lock
+        let state = cache.read_state();
+        assert_eq!(BlockingCache::get::<i32>(&cache, "a").unwrap(), Some(1));
+        drop(state);
+    }
+
+    #[test]
+    fn lists_only_live_entries() {
+        let cache = MemoryCache::new();
+        let hour = Duration::from_secs(60 * 60);
+        let before = SystemTime::now();
+        BlockingCache::set(&cache, "never", 1, None).unwrap();
+        BlockingCache::set(&cache, "ttl", 22, hour).unwrap();
+        BlockingCache::set(&cache, "tti", 333, ExpiryPolicy::tti(hour)).unwrap();
+        BlockingCache::set(&cache, "expired", 4, Duration::ZERO).unwrap();
+        let after = SystemTime::now();
+
+        let mut entries: Vec<_> = block_on(collect(cache.entries()))
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        let keys = ["never", "tti", "ttl"].map(EncodedKey::new);
+        assert_eq!(
+            entries
+                .iter()
+                .map(|info| info.key.clone())
+                .collect::<Vec<_>>(),
+            keys
+        );
+        for (info, value_len) in entries.iter().zip([1, 3, 2]) {
+            assert_eq!(info.size, (info.key.as_bytes().len() + value_len) as u64);
+            assert!(before <= info.created_at && info.created_at <= after);
+        }
+        assert_eq!(entries[0].expires_at, None);
+        // Deadlines are tracked on the monotonic clock, converting them back is approximate
+        let slack = Duration::from_secs(1);
+        for info in &entries[1..] {
+            let expires_at = info.expires_at.unwrap();
+            assert!(before + hour - slack <= expires_at && expires_at <= after + hour + slack);
+        }
+
+        let mut listed: Vec<_> = block_on(collect(cache.keys()))
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        listed.sort();
+        assert_eq!(listed, keys);
+    }
+}
@@ -1,3 +1,10 @@
 pub mod fs_cache;
+pub mod memory_cache;
+pub mod tiered_cache;
 
-pub use fs_cache::FsCache;
+pub use fs_cache::{
+    BackgroundGc, Entries, EvictionPolicy, FsCache, FsCacheBuilder, FsyncPolicy, GcHandle, GcStats,
+    Keys, ManifestMismatch,
+};
+pub use memory_cache::{MemoryCache, MemoryCacheBuilder};
+pub use tiered_cache::TieredCache;
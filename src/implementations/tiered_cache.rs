@@ -0,0 +1,510 @@
+use std::{
+    future::Future,
+    hash::Hash,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    expiry::ExpiryPolicy,
+    key_hasher::EncodedKey,
+    simple_cache::{BlockingCache, RawEntry, SendCache},
+    single_flight::{self, SingleFlight},
+};
+
+/// Reads through a fast `L1` (usually a `MemoryCache`) before falling back to
+/// `L2` (usually an `FsCache`).
+///
+/// Hits in `L2` are copied into `L1`, so an entry never outlives its `L2`
+/// copy. Entries with a time-to-idle stay in `L2` only, since reads served by
+/// `L1` wouldn't keep the `L2` entry from going idle.
+///
+/// Writes and invalidations go to `L2` first, and a read never copies a
+/// value into `L1` that a concurrent write or invalidation through this cache
+/// has replaced. Changes other processes make to a shared `L2` only show
+/// once the `L1` copy expires. Values are serialized with `L2`'s codec, so
+/// both tiers should use the same one, otherwise every read falls through
+/// to `L2`.
+pub struct TieredCache<L1, L2> {
+    l1: L1,
+    l2: L2,
+    flights: SingleFlight,
+    // Per-key locks serializing changes with copying values into L1
+    writes: SingleFlight,
+    // Counts finished changes, so a read can tell whether one happened since
+    // it read L2
+    epoch: AtomicU64,
+}
+
+impl<L1, L2> TieredCache<L1, L2> {
+    pub fn new(l1: L1, l2: L2) -> Self {
+        Self {
+            l1,
+            l2,
+            flights: SingleFlight::default(),
+            writes: SingleFlight::default(),
+            epoch: AtomicU64::new(0),
+        }
+    }
+
+    pub fn l1(&self) -> &L1 {
+        &self.l1
+    }
+
+    pub fn l2(&self) -> &L2 {
+        &self.l2
+    }
+}
+
+impl<L1, L2> TieredCache<L1, L2>
+where
+    L1: SendCache,
+    L2: SendCache<Error = L1::Error>,
+{
+    async fn fetch(&self, key: EncodedKey) -> Result<Option<RawEntry>, L1::Error> {
+        if let Some(entry) = self.l1.get_raw(key.clone()).await? {
+            return Ok(Some(entry));
+        }
+
+        let epoch = self.epoch.load(Ordering::SeqCst);
+        match self.l2.get_raw(key.clone()).await? {
+            Some(entry) => {
+                let _write = self.writes.join(key.as_bytes().to_vec()).await;
+                // Anything changed since L2 was read may have replaced or removed the entry,
+                // and the value read must not come back to life in L1
+                if self.epoch.load(Ordering::SeqCst) == epoch && fits_l1(&entry) {
+                    // The value was read fine, failing to promote it
+                    // (e.g. because it doesn't fit in L1) only costs another L2 read later
+                    let _ = self.l1.set_raw(key, entry.clone()).await;
+                }
+                Ok(Some(entry))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn store(&self, key: EncodedKey, entry: RawEntry) -> Result<(), L1::Error> {
+        let _write = self.writes.join(key.as_bytes().to_vec()).await;
+        let res = async {
+            self.l2.set_raw(key.clone(), entry.clone()).await?;
+            // L2 has the value, so an entry L1 refuses or mustn't hold
+            // only has to be kept from shadowing it
+            if !fits_l1(&entry) || self.l1.set_raw(key.clone(), entry).await.is_err() {
+                self.l1.invalidate(key).await?;
+            }
+            Ok(())
+        }
+        .await;
+        // Even a failed change may have touched L2
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        res
+    }
+
+    async fn remove(&self, key: EncodedKey) -> Result<(), L1::Error> {
+        let _write = self.writes.join(key.as_bytes().to_vec()).await;
+        let res = async {
+            self.l2.invalidate(key.clone()).await?;
+            self.l1.invalidate(key).await
+        }
+        .await;
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        res
+    }
+}
+
+// Reads of an L1 copy don't reach L2, so an entry that only stays alive
+// while it is read would go idle in L2 while L1 keeps serving it.
+// And only L2 knows what its default expiry is
+fn fits_l1(entry: &RawEntry) -> bool {
+    entry.idle_timeout.is_none() && !entry.uses_default_expiry
+}
+
+impl<L1, L2> SendCache for TieredCache<L1, L2>
+where
+    L1: SendCache + Sync,
+    L2: SendCache<Error = L1::Error> + Sync,
+    L1::Error: Send,
+{
+    type Error = L1::Error;
+    fn set(
+        &self,
+        key: impl Hash,
+        value: impl Serialize,
+        expiry: impl Into<ExpiryPolicy>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        let key = EncodedKey::new(key);
+        let entry = self.l2.encode_raw(value, expiry.into());
+        async move { self.store(key, entry?).await }
+    }
+
+    fn get<T>(&self, key: impl Hash) -> impl Future<Output = Result<Option<T>, Self::Error>> + Send
+    where
+        T: DeserializeOwned,
+    {
+        let key = EncodedKey::new(key);
+        async move {
+            match self.fetch(key).await? {
+                Some(entry) => self.decode_raw(&entry),
+                None => Ok(None),
+            }
+        }
+    }
+
+    fn invalidate(&self, key: impl Hash) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        self.remove(EncodedKey::new(key))
+    }
+
+    async fn collect_garbage(&self) -> Result<(), Self::Error> {
+        self.l2.collect_garbage().await?;
+        self.l1.collect_garbage().await
+    }
+
+    fn get_or_insert_with<T, E, F, Fut>(
+        &self,
+        key: impl Hash,
+        expiry: impl Into<ExpiryPolicy>,
+        loader: F,
+    ) -> impl Future<Output = Result<T, E>> + Send
+    where
+        T: Serialize + DeserializeOwned + Send,
+        E: From<Self::Error> + Send,
+        F: FnOnce() -> Fut + Send,
+        Fut: Future<Output = Result<T, E>> + Send,
+    {
+        single_flight::get_or_insert_with(
+            self,
+            &self.flights,
+            EncodedKey::new(key),
+            expiry.into(),
+            loader,
+        )
+    }
+
+    fn get_raw(
+        &self,
+        key: impl Hash,
+    ) -> impl Future<Output = Result<Option<RawEntry>, Self::Error>> + Send {
+        self.fetch(EncodedKey::new(key))
+    }
+
+    fn set_raw(
+        &self,
+        key: impl Hash,
+        entry: RawEntry,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        self.store(EncodedKey::new(key), entry)
+    }
+
+    fn encode_raw(
+        &self,
+        value: impl Serialize,
+        expiry: ExpiryPolicy,
+    ) -> Result<RawEntry, Self::Error> {
+        self.l2.encode_raw(value, expiry)
+    }
+
+    fn decode_raw<T>(&self, entry: &RawEntry) -> Result<Option<T>, Self::Error>
+    where
+        T: DeserializeOwned,
+    {
+        match self.l1.decode_raw(entry)? {
+            Some(value) => Ok(Some(value)),
+            None => self.l2.decode_raw(entry),
+        }
+    }
+}
+
+impl<L1, L2> BlockingCache for TieredCache<L1, L2>
+where
+    L1: BlockingCache,
+    L2: BlockingCache<Error = L1::Error>,
+{
+    type Error = L1::Error;
+    fn set(
+        &self,
+        key: impl Hash,
+        value: impl Serialize,
+        expiry: impl Into<ExpiryPolicy>,
+    ) -> Result<(), Self::Error> {
+        let entry = self.l2.encode_raw(value, expiry.into())?;
+        BlockingCache::set_raw(self, key, entry)
+    }
+
+    fn get<T>(&self, key: impl Hash) -> Result<Option<T>, Self::Error>
+    where
+        T: DeserializeOwned,
+    {
+        match BlockingCache::get_raw(self, key)? {
+            Some(entry) => BlockingCache::decode_raw(self, &entry),
+            None => Ok(None),
+        }
+    }
+
+    fn invalidate(&self, key: impl Hash) -> Result<(), Self::Error> {
+        // Same as `remove`
+        let key = EncodedKey::new(key);
+        let _write = self.writes.join_blocking(key.as_bytes().to_vec());
+        let res = self
+            .l2
+            .invalidate(key.clone())
+            .and_then(|()| self.l1.invalidate(key));
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        res
+    }
+
+    fn collect_garbage(&self) -> Result<(), Self::Error> {
+        self.l2.collect_garbage()?;
+        self.l1.collect_garbage()
+    }
+
+    fn get_raw(&self, key: impl Hash) -> Result<Option<RawEntry>, Self::Error> {
+        // Same as `fetch`
+        let key = EncodedKey::new(key);
+        if let Some(entry) = self.l1.get_raw(key.clone())? {
+            return Ok(Some(entry));
+        }
+
+        let epoch = self.epoch.load(Ordering::SeqCst);
+        match self.l2.get_raw(key.clone())? {
+            Some(entry) => {
+                let _write = self.writes.join_blocking(key.as_bytes().to_vec());
+                if self.epoch.load(Ordering::SeqCst) == epoch && fits_l1(&entry) {
+                    let _ = self.l1.set_raw(key, entry.clone());
+                }
+                Ok(Some(entry))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn set_raw(&self, key: impl Hash, entry: RawEntry) -> Result<(), Self::Error> {
+        // Same as `store`
+        let key = EncodedKey::new(key);
+        let _write = self.writes.join_blocking(key.as_bytes().to_vec());
+        let res = self.l2.set_raw(key.clone(), entry.clone()).and_then(|()| {
+            if !fits_l1(&entry) || self.l1.set_raw(key.clone(), entry).is_err() {
+                self.l1.invalidate(key)?;
+            }
+            Ok(())
+        });
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        res
+    }
+
+    fn encode_raw(
+        &self,
+        value: impl Serialize,
+        expiry: ExpiryPolicy,
+    ) -> Result<RawEntry, Self::Error> {
+        self.l2.encode_raw(value, expiry)
+    }
+
+    fn decode_raw<T>(&self, entry: &RawEntry) -> Result<Option<T>, Self::Error>
+    where
+        T: DeserializeOwned,
+    {
+        match self.l1.decode_raw(entry)? {
+            Some(value) => Ok(Some(value)),
+            None => self.l2.decode_raw(entry),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::AtomicBool, time::Duration};
+
+    use async_lock::Mutex;
+
+    use super::*;
+    use crate::{
+        error::CacheError,
+        implementations::{FsCache, MemoryCache},
+        test_util::TempDir,
+    };
+
+    // A cache whose reads wait at a gate the test holds, after reading
+    struct Gated {
+        inner: MemoryCache,
+        gate: Mutex<()>,
+        reading: AtomicBool,
+    }
+
+    impl Gated {
+        fn new() -> Self {
+            Self {
+                inner: MemoryCache::new(),
+                gate: Mutex::new(()),
+                reading: AtomicBool::new(false),
+            }
+        }
+
+        async fn wait_for_reader(&self) {
+            while !self.reading.load(Ordering::SeqCst) {
+                tokio::task::yield_now().await;
+            }
+        }
+    }
+
+    impl SendCache for Gated {
+        type Error = CacheError;
+        fn set(
+            &self,
+            key: impl Hash,
+            value: impl Serialize,
+            expiry: impl Into<ExpiryPolicy>,
+        ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+            SendCache::set(&self.inner, key, value, expiry)
+        }
+
+        fn get<T>(
+            &self,
+            key: impl Hash,
+        ) -> impl Future<Output = Result<Option<T>, Self::Error>> + Send
+        where
+            T: DeserializeOwned,
+        {
+            SendCache::get(&self.inner, key)
+        }
+
+        fn invalidate(
+            &self,
+            key: impl Hash,
+        ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+            SendCache::invalidate(&self.inner, key)
+        }
+
+        async fn collect_garbage(&self) -> Result<(), Self::Error> {
+            SendCache::collect_garbage(&self.inner).await
+        }
+
+        fn get_or_insert_with<T, E, F, Fut>(
+            &self,
+            key: impl Hash,
+            expiry: impl Into<ExpiryPolicy>,
+            loader: F,
+        ) -> impl Future<Output = Result<T, E>> + Send
+        where
+            T: Serialize + DeserializeOwned + Send,
+            E: From<Self::Error> + Send,
+            F: FnOnce() -> Fut + Send,
+            Fut: Future<Output = Result<T, E>> + Send,
+        {
+            SendCache::get_or_insert_with(&self.inner, key, expiry, loader)
+        }
+
+        fn get_raw(
+            &self,
+            key: impl Hash,
+        ) -> impl Future<Output = Result<Option<RawEntry>, Self::Error>> + Send {
+            let read = SendCache::get_raw(&self.inner, key);
+            async move {
+                let entry = read.await;
+                self.reading.store(true, Ordering::SeqCst);
+                let _gate = self.gate.lock().await;
+                entry
+            }
+        }
+
+        fn set_raw(
+            &self,
+            key: impl Hash,
+            entry: RawEntry,
+        ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+            SendCache::set_raw(&self.inner, key, entry)
+        }
+
+        fn encode_raw(
+            &self,
+            value: impl Serialize,
+            expiry: ExpiryPolicy,
+        ) -> Result<RawEntry, Self::Error> {
+            SendCache::encode_raw(&self.inner, value, expiry)
+        }
+
+        fn decode_raw<T>(&self, entry: &RawEntry) -> Result<Option<T>, Self::Error>
+        where
+            T: DeserializeOwned,
+        {
+            SendCache::decode_raw(&self.inner, entry)
+        }
+    }
+
+    async fn cache_with_l2_only(value: i32) -> TieredCache<MemoryCache, Gated> {
+        let cache = TieredCache::new(MemoryCache::new(), Gated::new());
+        SendCache::set(&cache.l2().inner, "k", value, None)
+            .await
+            .unwrap();
+        cache
+    }
+
+    #[tokio::test]
+    async fn reads_dont_promote_values_replaced_meanwhile() {
+        let cache = cache_with_l2_only(1).await;
+        let gate = cache.l2().gate.lock().await;
+
+        let (read, ()) = tokio::join!(SendCache::get::<i32>(&cache, "k"), async {
+            cache.l2().wait_for_reader().await;
+            SendCache::set(&cache, "k", 2, None).await.unwrap();
+            drop(gate);
+        });
+
+        assert_eq!(read.unwrap(), Some(1));
+        assert_eq!(
+            SendCache::get::<i32>(cache.l1(), "k").await.unwrap(),
+            Some(2)
+        );
+        assert_eq!(SendCache::get::<i32>(&cache, "k").await.unwrap(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn reads_dont_promote_values_invalidated_meanwhile() {
+        let cache = cache_with_l2_only(1).await;
+        let gate = cache.l2().gate.lock().await;
+
+        let (read, ()) = tokio::join!(SendCache::get::<i32>(&cache, "k"), async {
+            cache.l2().wait_for_reader().await;
+            SendCache::invalidate(&cache, "k").await.unwrap();
+            drop(gate);
+        });
+
+        assert_eq!(read.unwrap(), Some(1));
+        assert_eq!(SendCache::get::<i32>(cache.l1(), "k").await.unwrap(), None);
+        assert_eq!(SendCache::get::<i32>(&cache, "k").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn promotes_hits_in_l2() {
+        let cache = cache_with_l2_only(1).await;
+        assert_eq!(SendCache::get::<i32>(&cache, "k").await.unwrap(), Some(1));
+        assert_eq!(
+            SendCache::get::<i32>(cache.l1(), "k").await.unwrap(),
+            Some(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_keep_idle_entries_alive() {
+        let dir = TempDir::new();
+        let l2 = FsCache::new(dir.path().to_path_buf()).unwrap();
+        let cache = TieredCache::new(MemoryCache::new(), l2);
+        let tti = Duration::from_millis(300);
+        SendCache::set(&cache, "idle", 1, ExpiryPolicy::tti(tti))
+            .await
+            .unwrap();
+
+        for _ in 0..6 {
+            tokio::time::sleep(tti / 3).await;
+            assert_eq!(
+                SendCache::get::<i32>(&cache, "idle").await.unwrap(),
+                Some(1)
+            );
+        }
+        // Every read went to L2
+        assert_eq!(SendCache::get_raw(cache.l1(), "idle").await.unwrap(), None);
+
+        tokio::time::sleep(tti * 2).await;
+        assert_eq!(SendCache::get::<i32>(&cache, "idle").await.unwrap(), None);
+    }
+}
@@ -0,0 +1,180 @@
+use siphasher::sip::SipHasher13;
+use std::hash::{Hash, Hasher};
+
+/// Maps the canonical encoding of a key (see [`encode_key`]) to the `u64`
+/// a cache uses to address it.
+///
+/// The output must be stable across processes, platforms and compiler
+/// releases, since `FsCache` derives file names from it. `id` is recorded in
+/// the cache manifest, so it has to change whenever the output does.
+///
+/// The input is only as stable as [`encode_key`], see there.
+pub trait KeyHasher {
+    fn id(&self) -> &str;
+    fn hash(&self, key: &[u8]) -> u64;
+}
+
+/// SipHash-1-3 with fixed keys. This is the default [`KeyHasher`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SipKeyHasher;
+
+// Arbitrary, but must never change: every file name depends on them
+const SIP_K0: u64 = 0x7369_6d70_6c65_5f63;
+const SIP_K1: u64 = 0x6163_6865_5f6b_6579;
+
+impl KeyHasher for SipKeyHasher {
+    fn id(&self) -> &str {
+        "siphash13-v1"
+    }
+
+    fn hash(&self, key: &[u8]) -> u64 {
+        let mut hasher = SipHasher13::new_with_keys(SIP_K0, SIP_K1);
+        hasher.write(key);
+        hasher.finish()
+    }
+}
+
+/// Returns the bytes `key` feeds to its `Hash` implementation.
+///
+/// Integers are written little-endian and `usize`/`isize` are widened to 64
+/// bits, so the encoding is the same on every platform.
+///
+/// Which bytes a type feeds is up to its `Hash` implementation, and the
+/// standard library doesn't promise to keep feeding the same ones across
+/// compiler releases (e.g. how a `str` is terminated or a slice's length is
+/// written). `FsCache` records a fingerprint of the encoding in its manifest,
+/// so a change is reported when the cache is opened instead of silently
+/// turning every entry into a miss.
+pub fn encode_key(key: impl Hash) -> Vec<u8> {
+    let mut encoder = KeyEncoder(Vec::new());
+    key.hash(&mut encoder);
+    encoder.0
+}
+
+/// A key that has already been through [`encode_key`].
+///
+/// Hashing it feeds exactly the encoded bytes, so every cache in this crate
+/// treats it like the original key. Unlike the original it is always `Send`
+/// and `Clone`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EncodedKey(Vec<u8>);
+
+impl EncodedKey {
+    pub fn new(key: impl Hash) -> Self {
+        Self(encode_key(key))
+    }
+
+    /// Wraps bytes produced by [`encode_key`].
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+impl Hash for EncodedKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write(&self.0);
+    }
+}
+
+/// Fingerprints how [`encode_key`] encodes the standard types keys are
+/// usually made of, to tell whether it changed since a cache was written.
+pub(crate) fn key_encoding_fingerprint() -> String {
+    let probe = encode_key((
+        "str",
+        String::from("string"),
+        [1u16, 2].as_slice(),
+        vec![String::from("a"), String::from("b")],
+        (Some(1u8), None::<u8>),
+        (true, 'c', -1i64, 1usize, u128::MAX),
+    ));
+    format!("{:016x}", SipKeyHasher.hash(&probe))
+}
+
+struct KeyEncoder(Vec<u8>);
+
+impl Hasher for KeyEncoder {
+    fn finish(&self) -> u64 {
+        // Only used to record the byte stream, never to produce a hash
+        0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_i16(&mut self, i: i16) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_i32(&mut self, i: i32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_i64(&mut self, i: i64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_i128(&mut self, i: i128) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_isize(&mut self, i: isize) {
+        self.write_i64(i as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_keys_like_it_always_did() {
+        assert_eq!(encode_key(1u32), [1, 0, 0, 0]);
+        assert_eq!(encode_key(1usize), [1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(encode_key(-2i16), [0xfe, 0xff]);
+        assert_eq!(encode_key("ab"), [b'a', b'b', 0xff]);
+        assert_eq!(encode_key([7u8].as_slice()), [1, 0, 0, 0, 0, 0, 0, 0, 7]);
+        assert_eq!(encode_key(("a", 1u8)), [b'a', 0xff, 1]);
+    }
+
+    #[test]
+    fn encoded_keys_hash_like_the_original() {
+        let key = ("user", 42u64);
+        assert_eq!(encode_key(EncodedKey::new(key)), encode_key(key));
+    }
+
+    #[test]
+    fn sip_key_hasher_is_stable() {
+        // Existing caches are named after these, changing them needs a new `id`
+        assert_eq!(SipKeyHasher.hash(b""), 6673557459350966712);
+        assert_eq!(SipKeyHasher.hash(b"key"), 12966809491803678778);
+    }
+}
@@ -0,0 +1,65 @@
+use std::hash::Hash;
+
+/// A key made of path-like segments, e.g. `user/42/profile`.
+///
+/// It hashes like any other key, so every cache accepts it. `FsCache` can
+/// also index the prefixes of paths set with `set_path`, so everything under
+/// `user/42` can be invalidated at once with `invalidate_prefix`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KeyPath {
+    segments: Vec<String>,
+}
+
+impl KeyPath {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns this path with `segment` appended.
+    pub fn join(mut self, segment: impl Into<String>) -> Self {
+        self.segments.push(segment.into());
+        self
+    }
+
+    pub fn segments(&self) -> &[String] {
+        &self.segments
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Whether the first segments of this path are those of `prefix`. Every
+    /// path starts with itself.
+    pub fn starts_with(&self, prefix: &KeyPath) -> bool {
+        self.segments.starts_with(&prefix.segments)
+    }
+
+    /// The non-empty prefixes of this path, shortest first, ending with the
+    /// path itself.
+    pub fn prefixes(&self) -> impl Iterator<Item = KeyPath> + '_ {
+        (1..=self.segments.len()).map(|len| KeyPath {
+            segments: self.segments[..len].to_vec(),
+        })
+    }
+}
+
+impl<S: Into<String>> FromIterator<S> for KeyPath {
+    fn from_iter<I: IntoIterator<Item = S>>(segments: I) -> Self {
+        Self {
+            segments: segments.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl<S: Into<String>, const N: usize> From<[S; N]> for KeyPath {
+    fn from(segments: [S; N]) -> Self {
+        segments.into_iter().collect()
+    }
+}
+
+impl<S: Into<String>> From<Vec<S>> for KeyPath {
+    fn from(segments: Vec<S>) -> Self {
+        segments.into_iter().collect()
+    }
+}
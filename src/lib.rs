@@ -1,4 +1,17 @@
+pub mod codec;
+pub mod dyn_cache;
+pub mod error;
+pub mod expiry;
 pub mod implementations;
+pub mod key_hasher;
+pub mod key_path;
 pub mod simple_cache;
+mod single_flight;
+#[cfg(test)]
+mod test_util;
 
+pub use dyn_cache::{DynCache, TypedCache};
+pub use error::CacheError;
+pub use expiry::{Expiry, ExpiryPolicy};
 pub use implementations::*;
+pub use key_path::KeyPath;
@@ -1,18 +1,364 @@
 use serde::{de::DeserializeOwned, Serialize};
-use std::{hash::Hash, time::Duration};
+use std::{
+    future::Future,
+    hash::Hash,
+    time::{Duration, SystemTime},
+};
 
-#[trait_variant::make()]
+use crate::{
+    codec::Codec,
+    error::CacheError,
+    expiry::{Expiry, ExpiryPolicy},
+    key_hasher::EncodedKey,
+};
+
+/// A value the way a cache stores it, serialized by the codec with id `codec`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawEntry {
+    pub codec: u8,
+    pub value: Vec<u8>,
+    /// When the entry expires however often it is read
+    pub expires_at: Option<SystemTime>,
+    /// How long the entry may go unread, counted from when it is stored
+    pub idle_timeout: Option<Duration>,
+    /// Whether the expiry was left to the cache ([`Expiry::Default`]), in
+    /// which case `set_raw` applies the default of the cache storing it
+    pub uses_default_expiry: bool,
+}
+
+impl RawEntry {
+    /// Serializes `value` with `codec`, the way every cache's `encode_raw` does.
+    pub(crate) fn encode(
+        codec: &impl Codec,
+        value: impl Serialize,
+        expiry: ExpiryPolicy,
+    ) -> Result<Self, CacheError> {
+        Ok(Self {
+            codec: codec.id(),
+            value: codec.encode(&value).map_err(CacheError::Serialize)?,
+            expires_at: expiry.expiry().expires_at(),
+            idle_timeout: expiry.time_to_idle(),
+            uses_default_expiry: expiry.expiry() == Expiry::Default,
+        })
+    }
+
+    /// Applies `default` to an entry that left its expiry to the cache, the
+    /// way `ExpiryPolicy::or` would have when it was encoded.
+    pub(crate) fn or_expiry(self, default: ExpiryPolicy) -> Self {
+        if !self.uses_default_expiry {
+            return self;
+        }
+        Self {
+            expires_at: default.expiry().expires_at(),
+            idle_timeout: self.idle_timeout.or(default.time_to_idle()),
+            uses_default_expiry: false,
+            ..self
+        }
+    }
+
+    /// Deserializes the value with `codec`, the way every cache's
+    /// `decode_raw` does.
+    pub(crate) fn decode<T: DeserializeOwned>(
+        &self,
+        codec: &impl Codec,
+    ) -> Result<Option<T>, CacheError> {
+        // An entry written with another codec can't be decoded with ours
+        if self.codec != codec.id() {
+            return Ok(None);
+        }
+        codec
+            .decode(&self.value)
+            .map(Some)
+            .map_err(CacheError::Deserialize)
+    }
+}
+
+/// What a cache knows about one of its entries, see e.g. `FsCache::entries`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryInfo {
+    /// The key as [`encode_key`](crate::key_hasher::encode_key) encoded it.
+    /// Caches only keep the encoding, not the original key
+    pub key: EncodedKey,
+    /// Bytes the entry takes up in the cache
+    pub size: u64,
+    pub created_at: SystemTime,
+    /// When the entry expires unless it is read before
+    pub expires_at: Option<SystemTime>,
+}
+
+/// A cache whose entries can expire.
+///
+/// `SendCache` is the same trait with `Send` futures, so it can be used from
+/// `tokio::spawn` and other multi-threaded executors. Implement `SendCache`,
+/// `Cache` comes with it for free. Every implementation in this crate does so.
+#[trait_variant::make(SendCache: Send)]
 pub trait Cache {
     type Error;
+    /// Stores `value` under `key`, replacing any previous entry together with
+    /// all of its metadata. `expiry` is an [`ExpiryPolicy`], or anything that
+    /// converts into an [`Expiry`](crate::expiry::Expiry) such as a time-to-live
+    /// or an instant. `None` makes the entry permanent even if the key was
+    /// previously set with an expiry, unless the cache has a default expiry
+    /// (see e.g. `FsCacheBuilder::default_expiry`). [`ExpiryPolicy::NEVER`]
+    /// makes it permanent either way.
     async fn set(
         &self,
         key: impl Hash,
         value: impl Serialize,
-        expiry: Option<Duration>,
+        expiry: impl Into<ExpiryPolicy>,
     ) -> Result<(), Self::Error>;
-    async fn get<'a, T>(&self, key: impl Hash) -> Result<Option<T>, Self::Error>
+    /// Returns `Ok(None)` when the key was never set, has expired or was
+    /// invalidated. Errors are reserved for failures of the cache itself.
+    async fn get<T>(&self, key: impl Hash) -> Result<Option<T>, Self::Error>
     where
         T: DeserializeOwned;
+    /// Removes the entry for `key`, if any. A later `set` starts from scratch,
+    /// nothing about the invalidated entry carries over.
     async fn invalidate(&self, key: impl Hash) -> Result<(), Self::Error>;
+    /// Removes expired entries. Entries that disappear while the pass is
+    /// running are not an error.
     async fn collect_garbage(&self) -> Result<(), Self::Error>;
+    /// Returns the value for `key`, or runs `loader`, stores what it returns
+    /// with `expiry` and returns that.
+    ///
+    /// Concurrent calls for the same key on the same cache run `loader` only
+    /// once, the others wait for it and then read the stored value. A loader
+    /// that fails stores nothing, and the next waiter runs its own loader.
+    async fn get_or_insert_with<T, E, F, Fut>(
+        &self,
+        key: impl Hash,
+        expiry: impl Into<ExpiryPolicy>,
+        loader: F,
+    ) -> Result<T, E>
+    where
+        T: Serialize + DeserializeOwned + Send,
+        E: From<Self::Error> + Send,
+        F: FnOnce() -> Fut + Send,
+        Fut: Future<Output = Result<T, E>> + Send;
+
+    /// Looks up every key, returning one result per key in the same order.
+    fn get_many<T, K>(
+        &self,
+        keys: impl IntoIterator<Item = K>,
+    ) -> impl Future<Output = Vec<Result<Option<T>, Self::Error>>>
+    where
+        T: DeserializeOwned + Send,
+        K: Hash,
+        Self: Sync,
+        Self::Error: Send,
+    {
+        let keys: Vec<_> = keys.into_iter().map(EncodedKey::new).collect();
+        async move {
+            let mut results = Vec::with_capacity(keys.len());
+            for key in keys {
+                results.push(self.get(key).await);
+            }
+            results
+        }
+    }
+
+    /// Stores every value with the same `expiry`, returning one result per
+    /// entry in the same order. A failed entry doesn't keep the others from
+    /// being stored.
+    fn set_many<K, V>(
+        &self,
+        entries: impl IntoIterator<Item = (K, V)>,
+        expiry: impl Into<ExpiryPolicy>,
+    ) -> impl Future<Output = Vec<Result<(), Self::Error>>>
+    where
+        K: Hash,
+        V: Serialize,
+        Self: Sync,
+        Self::Error: Send,
+    {
+        let expiry = expiry.into();
+        let entries: Vec<_> = entries
+            .into_iter()
+            .map(|(key, value)| (EncodedKey::new(key), self.encode_raw(value, expiry)))
+            .collect();
+        async move {
+            let mut results = Vec::with_capacity(entries.len());
+            for (key, entry) in entries {
+                results.push(match entry {
+                    Ok(entry) => self.set_raw(key, entry).await,
+                    Err(e) => Err(e),
+                });
+            }
+            results
+        }
+    }
+
+    /// Invalidates every key, returning one result per key in the same order.
+    fn invalidate_many<K>(
+        &self,
+        keys: impl IntoIterator<Item = K>,
+    ) -> impl Future<Output = Vec<Result<(), Self::Error>>>
+    where
+        K: Hash,
+        Self: Sync,
+        Self::Error: Send,
+    {
+        let keys: Vec<_> = keys.into_iter().map(EncodedKey::new).collect();
+        async move {
+            let mut results = Vec::with_capacity(keys.len());
+            for key in keys {
+                results.push(self.invalidate(key).await);
+            }
+            results
+        }
+    }
+
+    /// Like `get`, but returns the value still serialized, along with when it
+    /// expires.
+    async fn get_raw(&self, key: impl Hash) -> Result<Option<RawEntry>, Self::Error>;
+    /// Stores a value that is already serialized, e.g. one read from another
+    /// cache with `get_raw`.
+    async fn set_raw(&self, key: impl Hash, entry: RawEntry) -> Result<(), Self::Error>;
+    /// Serializes `value` the way `set` would store it.
+    fn encode_raw(
+        &self,
+        value: impl Serialize,
+        expiry: ExpiryPolicy,
+    ) -> Result<RawEntry, Self::Error>;
+    /// Deserializes an entry returned by `get_raw`. Returns `None` if it was
+    /// written with a codec this cache does not use.
+    fn decode_raw<T>(&self, entry: &RawEntry) -> Result<Option<T>, Self::Error>
+    where
+        T: DeserializeOwned;
+}
+
+/// The blocking counterpart of [`Cache`], for callers without an async
+/// runtime. Methods behave exactly like their `Cache` namesakes.
+///
+/// Implementations that also implement `Cache` share its storage, so both
+/// APIs can be used on the same cache.
+pub trait BlockingCache {
+    type Error;
+    fn set(
+        &self,
+        key: impl Hash,
+        value: impl Serialize,
+        expiry: impl Into<ExpiryPolicy>,
+    ) -> Result<(), Self::Error>;
+    fn get<T>(&self, key: impl Hash) -> Result<Option<T>, Self::Error>
+    where
+        T: DeserializeOwned;
+    fn invalidate(&self, key: impl Hash) -> Result<(), Self::Error>;
+    fn collect_garbage(&self) -> Result<(), Self::Error>;
+
+    fn get_raw(&self, key: impl Hash) -> Result<Option<RawEntry>, Self::Error>;
+    fn set_raw(&self, key: impl Hash, entry: RawEntry) -> Result<(), Self::Error>;
+    fn encode_raw(
+        &self,
+        value: impl Serialize,
+        expiry: ExpiryPolicy,
+    ) -> Result<RawEntry, Self::Error>;
+    fn decode_raw<T>(&self, entry: &RawEntry) -> Result<Option<T>, Self::Error>
+    where
+        T: DeserializeOwned;
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::ser::{Error as _, Serializer};
+
+    use super::*;
+    use crate::{
+        error::CacheError, test_util::TempDir, FsCache, MemoryCache, TieredCache, TypedCache,
+    };
+
+    fn assert_send<T: Send>(_: T) {}
+
+    // Only has to compile, the futures are never polled
+    fn assert_futures_are_send<C>(cache: &C)
+    where
+        C: SendCache + Sync,
+        C::Error: Send,
+    {
+        assert_send(cache.set("key", 1, None));
+        assert_send(cache.get::<i32>("key"));
+        assert_send(cache.invalidate("key"));
+        assert_send(cache.collect_garbage());
+        assert_send(cache.get_or_insert_with("key", None, || async { Ok::<_, C::Error>(1) }));
+        assert_send(cache.get_many::<i32, _>(["a", "b"]));
+        assert_send(cache.set_many([("a", 1), ("b", 2)], None));
+        assert_send(cache.invalidate_many(["a", "b"]));
+        assert_send(cache.get_raw("key"));
+        if let Ok(entry) = cache.encode_raw(1, ExpiryPolicy::NEVER) {
+            assert_send(cache.set_raw("key", entry));
+        }
+    }
+
+    #[test]
+    fn futures_are_send() {
+        let dir = TempDir::new();
+        let fs_cache = FsCache::new(dir.join("fs")).unwrap();
+        assert_futures_are_send(&fs_cache);
+        assert_send(fs_cache.clear());
+        assert_futures_are_send(&MemoryCache::new());
+        let l2 = FsCache::new(dir.join("l2")).unwrap();
+        assert_futures_are_send(&TieredCache::new(MemoryCache::new(), l2));
+        assert_futures_are_send(&TypedCache::new(Box::new(MemoryCache::new())));
+
+        // Directly on the types, not only through the trait
+        assert_send(SendCache::get::<i32>(&fs_cache, "key"));
+        assert_send(SendCache::set(&MemoryCache::new(), "key", 1, None));
+    }
+
+    // Fails to serialize when there is no value
+    struct Value(Option<usize>);
+
+    impl Serialize for Value {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match self.0 {
+                Some(value) => value.serialize(serializer),
+                None => Err(S::Error::custom("no value")),
+            }
+        }
+    }
+
+    // Every fifth entry fails to serialize, and every third one is invalidated
+    async fn check_batches<C>(cache: &C, len: usize)
+    where
+        C: SendCache<Error = CacheError> + Sync,
+    {
+        let stored = |i: usize| !i.is_multiple_of(5);
+        let invalidated = |i: usize| i.is_multiple_of(3);
+
+        let entries = (0..len).map(|i| (i, Value(stored(i).then_some(i))));
+        let results = cache.set_many(entries, None).await;
+        assert_eq!(results.len(), len);
+        for (i, res) in results.into_iter().enumerate() {
+            assert_eq!(res.is_ok(), stored(i), "{i}: {res:?}");
+        }
+
+        let values = cache.get_many::<usize, _>(0..len).await;
+        let expected: Vec<_> = (0..len).map(|i| stored(i).then_some(i)).collect();
+        let values: Vec<_> = values.into_iter().map(Result::unwrap).collect();
+        assert_eq!(values, expected);
+
+        let results = cache
+            .invalidate_many((0..len).filter(|&i| invalidated(i)))
+            .await;
+        assert!(results.into_iter().all(|res| res.is_ok()));
+        let values = cache.get_many::<usize, _>(0..len).await;
+        for (i, value) in values.into_iter().enumerate() {
+            let expected = (stored(i) && !invalidated(i)).then_some(i);
+            assert_eq!(value.unwrap(), expected, "{i}");
+        }
+
+        assert!(cache.get_many::<usize, _>(0..0).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn batches_keep_order_and_fail_per_entry() {
+        // Enough entries for the file system cache to use several threads
+        for len in [3, 40] {
+            let dir = TempDir::new();
+            check_batches(&FsCache::new(dir.path().to_path_buf()).unwrap(), len).await;
+            // The default implementations
+            check_batches(&MemoryCache::new(), len).await;
+            check_batches(&TypedCache::new(Box::new(MemoryCache::new())), len).await;
+        }
+    }
 }
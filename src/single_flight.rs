@@ -0,0 +1,235 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use async_lock::{Mutex as AsyncMutex, MutexGuardArc};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{expiry::ExpiryPolicy, key_hasher::EncodedKey, simple_cache::SendCache};
+
+/// Tracks the loads in progress for a cache, so concurrent callers for the
+/// same key wait for one load instead of each running their own. Works as a
+/// per-key lock for anything else that needs one too.
+#[derive(Default)]
+pub(crate) struct SingleFlight {
+    in_flight: Mutex<HashMap<Vec<u8>, Arc<AsyncMutex<()>>>>,
+}
+
+// Held while loading a key. Dropping it lets the next caller in
+pub(crate) struct Flight<'a> {
+    flights: &'a SingleFlight,
+    key: Vec<u8>,
+    guard: Option<MutexGuardArc<()>>,
+}
+
+impl SingleFlight {
+    pub async fn join(&self, key: Vec<u8>) -> Flight<'_> {
+        let guard = self.lock_for(&key).lock_arc().await;
+        Flight {
+            flights: self,
+            key,
+            guard: Some(guard),
+        }
+    }
+
+    /// Like `join`, blocking the thread instead.
+    pub fn join_blocking(&self, key: Vec<u8>) -> Flight<'_> {
+        let guard = self.lock_for(&key).lock_arc_blocking();
+        Flight {
+            flights: self,
+            key,
+            guard: Some(guard),
+        }
+    }
+
+    fn lock_for(&self, key: &[u8]) -> Arc<AsyncMutex<()>> {
+        Arc::clone(
+            self.in_flight
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .entry(key.to_vec())
+                .or_default(),
+        )
+    }
+}
+
+impl Drop for Flight<'_> {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        let mut in_flight = self
+            .flights
+            .in_flight
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        // Nobody else is waiting once only the map holds the lock
+        if in_flight
+            .get(&self.key)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+/// `get_or_insert_with` for any cache, deduplicating loads through `flights`.
+pub(crate) async fn get_or_insert_with<C, T, E, F, Fut>(
+    cache: &C,
+    flights: &SingleFlight,
+    key: EncodedKey,
+    expiry: ExpiryPolicy,
+    loader: F,
+) -> Result<T, E>
+where
+    C: SendCache,
+    T: Serialize + DeserializeOwned,
+    E: From<C::Error>,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    if let Some(value) = cache.get(key.clone()).await? {
+        return Ok(value);
+    }
+
+    let _flight = flights.join(key.as_bytes().to_vec()).await;
+    // Whoever held the flight before us may have stored the value already
+    if let Some(value) = cache.get(key.clone()).await? {
+        return Ok(value);
+    }
+
+    let value = loader().await?;
+    cache.set(key, &value, expiry).await?;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::{error::CacheError, test_util::TempDir, FsCache, MemoryCache};
+
+    #[derive(Debug)]
+    enum LoadError {
+        #[allow(dead_code)]
+        Cache(CacheError),
+        Failed,
+    }
+
+    impl From<CacheError> for LoadError {
+        fn from(e: CacheError) -> Self {
+            Self::Cache(e)
+        }
+    }
+
+    // Gives the other tasks time to pile up behind the running loader
+    async fn slow_load(runs: &AtomicUsize, res: Result<u32, LoadError>) -> Result<u32, LoadError> {
+        runs.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        res
+    }
+
+    async fn check_runs_loader_once<C>(cache: Arc<C>)
+    where
+        C: SendCache<Error = CacheError> + Send + Sync + 'static,
+    {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let callers: Vec<_> = (0..16)
+            .map(|_| {
+                let (cache, runs) = (Arc::clone(&cache), Arc::clone(&runs));
+                tokio::spawn(async move {
+                    cache
+                        .get_or_insert_with("key", None, || slow_load(&runs, Ok(7)))
+                        .await
+                })
+            })
+            .collect();
+        for caller in callers {
+            assert_eq!(caller.await.unwrap().unwrap(), 7);
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.get::<u32>("key").await.unwrap(), Some(7));
+    }
+
+    async fn check_failed_loads_store_nothing<C>(cache: Arc<C>)
+    where
+        C: SendCache<Error = CacheError> + Send + Sync + 'static,
+    {
+        let res = cache
+            .get_or_insert_with("alone", None, || async { Err::<u32, _>(LoadError::Failed) })
+            .await;
+        assert!(matches!(res, Err(LoadError::Failed)));
+        assert_eq!(cache.get::<u32>("alone").await.unwrap(), None);
+
+        let failed_runs = Arc::new(AtomicUsize::new(0));
+        let failing = {
+            let (cache, runs) = (Arc::clone(&cache), Arc::clone(&failed_runs));
+            tokio::spawn(async move {
+                cache
+                    .get_or_insert_with("key", None, || slow_load(&runs, Err(LoadError::Failed)))
+                    .await
+            })
+        };
+        // Let the failing loader take the flight before anyone else asks
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        let runs = Arc::new(AtomicUsize::new(0));
+        let waiters: Vec<_> = (0..4)
+            .map(|_| {
+                let (cache, runs) = (Arc::clone(&cache), Arc::clone(&runs));
+                tokio::spawn(async move {
+                    cache
+                        .get_or_insert_with("key", None, || slow_load(&runs, Ok(7)))
+                        .await
+                })
+            })
+            .collect();
+
+        assert!(matches!(failing.await.unwrap(), Err(LoadError::Failed)));
+        for waiter in waiters {
+            assert_eq!(waiter.await.unwrap().unwrap(), 7);
+        }
+        assert_eq!(failed_runs.load(Ordering::SeqCst), 1);
+        // The first waiter in line loads for itself, the rest read what it stored
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    fn fs_cache(dir: &TempDir) -> Arc<FsCache> {
+        Arc::new(FsCache::new(dir.path().to_path_buf()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn fs_cache_runs_the_loader_once() {
+        let dir = TempDir::new();
+        check_runs_loader_once(fs_cache(&dir)).await;
+    }
+
+    #[tokio::test]
+    async fn memory_cache_runs_the_loader_once() {
+        check_runs_loader_once(Arc::new(MemoryCache::new())).await;
+    }
+
+    #[tokio::test]
+    async fn fs_cache_stores_nothing_for_failed_loads() {
+        let dir = TempDir::new();
+        check_failed_loads_store_nothing(fs_cache(&dir)).await;
+    }
+
+    #[tokio::test]
+    async fn memory_cache_stores_nothing_for_failed_loads() {
+        check_failed_loads_store_nothing(Arc::new(MemoryCache::new())).await;
+    }
+
+    #[test]
+    fn forgets_keys_nobody_waits_for() {
+        let flights = SingleFlight::default();
+        let flight = flights.join_blocking(b"key".to_vec());
+        assert_eq!(flights.in_flight.lock().unwrap().len(), 1);
+        drop(flight);
+        assert!(flights.in_flight.lock().unwrap().is_empty());
+    }
+}
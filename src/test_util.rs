@@ -0,0 +1,115 @@
+use std::{
+    env,
+    fmt::Debug,
+    fs,
+    future::{poll_fn, Future},
+    path::{Path, PathBuf},
+    pin::{pin, Pin},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+    thread,
+    time::Duration,
+};
+
+use futures_core::Stream;
+
+use crate::{simple_cache::BlockingCache, ExpiryPolicy};
+
+static NEXT_DIR: AtomicU64 = AtomicU64::new(0);
+
+/// A fresh directory for a test, removed again on drop.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let path = env::temp_dir().join(format!(
+            "simple-cache-test-{}-{}",
+            process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Checks that every `set` replaces the value and all metadata of the entry
+/// it overwrites, whatever happened to the key before.
+pub(crate) fn check_overwrite_semantics<C>(cache: &C)
+where
+    C: BlockingCache,
+    C::Error: Debug,
+{
+    let short = Duration::from_millis(50);
+    let get = |key| BlockingCache::get::<i32>(cache, key).unwrap();
+
+    // A later set without expiry makes the entry permanent
+    cache.set("ttl", 1, short).unwrap();
+    cache.set("ttl", 2, None).unwrap();
+    cache.set("tti", 1, ExpiryPolicy::tti(short)).unwrap();
+    cache.set("tti", 2, None).unwrap();
+    // And a later set with expiry makes a permanent entry expire
+    cache.set("never", 1, None).unwrap();
+    cache.set("never", 2, short).unwrap();
+
+    // Invalidating doesn't stick to the key
+    cache.set("invalidated", 1, None).unwrap();
+    cache.invalidate("invalidated").unwrap();
+    assert_eq!(get("invalidated"), None);
+    cache.set("invalidated", 2, None).unwrap();
+    assert_eq!(get("invalidated"), Some(2));
+    cache.invalidate("invalidated").unwrap();
+    cache.set("invalidated", 3, short).unwrap();
+    assert_eq!(get("invalidated"), Some(3));
+    cache.invalidate("invalidated").unwrap();
+    cache.set("invalidated", 4, None).unwrap();
+
+    // Neither does invalidating a key that was never set
+    cache.invalidate("fresh").unwrap();
+    cache.set("fresh", 1, None).unwrap();
+
+    thread::sleep(short * 2);
+    cache.collect_garbage().unwrap();
+    assert_eq!(get("ttl"), Some(2));
+    assert_eq!(get("tti"), Some(2));
+    assert_eq!(get("never"), None);
+    assert_eq!(get("invalidated"), Some(4));
+    assert_eq!(get("fresh"), Some(1));
+}
+
+/// Runs `future` to completion without any runtime, the way an executor
+/// other than tokio would.
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::yield_now();
+    }
+}
+
+/// Polls `stream` until it ends, returning everything it yielded.
+pub(crate) async fn collect<S: Stream + Unpin>(mut stream: S) -> Vec<S::Item> {
+    let mut items = Vec::new();
+    while let Some(item) = poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await {
+        items.push(item);
+    }
+    items
+}